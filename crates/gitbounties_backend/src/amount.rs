@@ -0,0 +1,217 @@
+//! Reward amounts and the assets they are paid in
+
+use std::{fmt, str::FromStr};
+
+use gitbounties_contract::{Address, U256};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Amount of an asset in its smallest unit, e.g. wei for ETH
+///
+/// Serialized as a decimal string, since neither JSON numbers nor database integers can hold
+/// every amount. Plain integers are still accepted when reading, for records stored before.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(pub U256);
+
+impl Amount {
+    pub fn saturating_add(self, other: Amount) -> Amount {
+        Amount(self.0.saturating_add(other.0))
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+}
+
+impl From<U256> for Amount {
+    fn from(value: U256) -> Self {
+        Amount(value)
+    }
+}
+
+impl From<u64> for Amount {
+    fn from(value: u64) -> Self {
+        Amount(U256::from(value))
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for Amount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(format!("invalid amount {s:?}, expected a decimal integer"));
+        }
+        U256::from_dec_str(s)
+            .map(Amount)
+            .map_err(|_| format!("amount {s} is too large"))
+    }
+}
+
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct AmountVisitor;
+
+        impl<'de> de::Visitor<'de> for AmountVisitor {
+            type Value = Amount;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a decimal string or a non negative integer")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Amount, E> {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Amount, E> {
+                Ok(Amount::from(v))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Amount, E> {
+                u64::try_from(v)
+                    .map(Amount::from)
+                    .map_err(|_| E::custom("amount can't be negative"))
+            }
+        }
+
+        deserializer.deserialize_any(AmountVisitor)
+    }
+}
+
+/// Asset an amount is denominated in
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Asset {
+    /// ERC-20 token contract, native ETH when unset
+    pub token: Option<Address>,
+    pub decimals: u8,
+    pub symbol: String,
+}
+
+impl Default for Asset {
+    fn default() -> Self {
+        Asset::native()
+    }
+}
+
+impl Asset {
+    pub fn native() -> Self {
+        Asset {
+            token: None,
+            decimals: 18,
+            symbol: "ETH".to_string(),
+        }
+    }
+
+    /// Amount in whole units followed by the symbol, e.g. `1.5 ETH`
+    pub fn format(&self, amount: Amount) -> String {
+        format!("{} {}", format_units(amount, self.decimals), self.symbol)
+    }
+}
+
+/// Decimal digits of the largest amount, `U256::MAX`
+const AMOUNT_DIGITS: usize = 78;
+
+/// Token of the asset named by a request, `ETH` for native ETH or an ERC-20 token address
+pub fn parse_asset_token(asset: &str) -> Result<Option<Address>, String> {
+    match asset.trim() {
+        "ETH" => Ok(None),
+        token => token
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("invalid asset {token:?}, expected ETH or a token address")),
+    }
+}
+
+/// Key ordering rewards exactly as strings: the asset followed by the amount zero padded to a
+/// fixed width, so rewards of one asset sort by amount and are grouped apart from other assets
+pub fn reward_key(token: Option<Address>, amount: Amount) -> String {
+    let asset = match token {
+        Some(token) => format!("{token:?}"),
+        None => "ETH".to_string(),
+    };
+    format!("{asset}:{:0>AMOUNT_DIGITS$}", amount.0.to_string())
+}
+
+/// Decimal representation of an amount with `decimals` fractional digits, without trailing zeros
+pub fn format_units(amount: Amount, decimals: u8) -> String {
+    let digits = amount.0.to_string();
+    let decimals = decimals as usize;
+
+    let (int, frac) = if digits.len() > decimals {
+        let (int, frac) = digits.split_at(digits.len() - decimals);
+        (int.to_string(), frac.to_string())
+    } else {
+        ("0".to_string(), format!("{digits:0>decimals$}"))
+    };
+
+    let frac = frac.trim_end_matches('0');
+    if frac.is_empty() {
+        int
+    } else {
+        format!("{int}.{frac}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gitbounties_contract::U256;
+
+    use super::{format_units, reward_key, Amount, Asset, AMOUNT_DIGITS};
+
+    #[test]
+    fn format_whole_and_fractional_units() {
+        let eth = Asset::native();
+        assert_eq!(
+            eth.format(Amount::from(1_500_000_000_000_000_000)),
+            "1.5 ETH"
+        );
+        assert_eq!(eth.format(Amount::from(2_000_000_000_000_000_000)), "2 ETH");
+        assert_eq!(eth.format(Amount::from(1)), "0.000000000000000001 ETH");
+        assert_eq!(eth.format(Amount::default()), "0 ETH");
+
+        assert_eq!(format_units(Amount::from(1_230_000), 6), "1.23");
+        assert_eq!(format_units(Amount::from(42), 0), "42");
+    }
+
+    #[test]
+    fn serialize_as_decimal_string() {
+        // more than u64 can hold
+        let amount = Amount(U256::from(u64::MAX) * 1000);
+        let json = serde_json::to_string(&amount).unwrap();
+        assert_eq!(json, "\"18446744073709551615000\"");
+        assert_eq!(serde_json::from_str::<Amount>(&json).unwrap(), amount);
+    }
+
+    #[test]
+    fn reward_keys_order_exactly() {
+        let wei = U256::exp10(18);
+        let key = |amount: U256| reward_key(None, Amount(amount));
+
+        assert!(key(wei) < key(wei + 1));
+        assert!(key(U256::from(9)) < key(U256::from(10)));
+        assert_eq!(key(U256::MAX).len(), "ETH:".len() + AMOUNT_DIGITS);
+        assert_ne!(reward_key(Some(Default::default()), Amount(wei)), key(wei));
+    }
+
+    #[test]
+    fn deserialize_legacy_integers() {
+        assert_eq!(
+            serde_json::from_str::<Amount>("1000").unwrap(),
+            Amount::from(1000)
+        );
+        assert!(serde_json::from_str::<Amount>("-1").is_err());
+        assert!(serde_json::from_str::<Amount>("\"1.5\"").is_err());
+    }
+}
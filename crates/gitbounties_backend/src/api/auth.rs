@@ -4,17 +4,103 @@ use axum::{
     routing::{get, post},
     Router,
 };
+use gitbounties_contract::parse_address;
 use log::debug;
-use serde::Deserialize;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
 
-use crate::AppState;
+use crate::{
+    models::User,
+    session_auth::{AuthUser, MyAuthContext},
+    siwe, wallet, AppState,
+};
 
 pub fn router() -> Router<AppState> {
     Router::new()
+        .route("/nonce", get(wallet_nonce))
+        .route("/siwe/nonce", get(siwe_nonce))
+        .route("/siwe/login", post(siwe_login))
     // .route("/register", post(register))
     // .route("/login", post(login))
 }
 
+#[derive(Debug, Deserialize)]
+pub struct NonceQuery {
+    wallet_address: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NonceResponse {
+    nonce: String,
+    /// Message the wallet should sign with `personal_sign`
+    message: String,
+}
+
+/// Issue a nonce used to prove ownership of a wallet
+async fn wallet_nonce(
+    State(state): State<AppState>,
+    Query(params): Query<NonceQuery>,
+) -> Result<Json<NonceResponse>, (StatusCode, &'static str)> {
+    let Ok(wallet_address) = parse_address(&params.wallet_address) else {
+        return Err((StatusCode::BAD_REQUEST, "invalid wallet address"));
+    };
+
+    let nonce = wallet::issue_nonce(&state, &wallet_address).await;
+    let message = wallet::ownership_message(&wallet_address, &nonce);
+
+    debug!("issued nonce for {wallet_address:?}");
+
+    Ok(Json(NonceResponse { nonce, message }))
+}
+
+/// Issue a nonce to embed in a Sign-In With Ethereum message
+async fn siwe_nonce(State(state): State<AppState>) -> String {
+    siwe::issue_nonce(&state).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SiweLoginBody {
+    /// EIP-4361 message that was signed
+    message: String,
+    signature: String,
+}
+
+/// Log in with a wallet that is linked to a user
+async fn siwe_login(
+    mut auth: MyAuthContext,
+    State(state): State<AppState>,
+    Json(payload): Json<SiweLoginBody>,
+) -> (StatusCode, &'static str) {
+    let address = match siwe::verify(&state, &payload.message, &payload.signature).await {
+        Ok(address) => address,
+        Err(err) => return err,
+    };
+
+    let mut res = state
+        .db_conn
+        .query(
+            "SELECT * FROM Users WHERE wallet_address == $address OR wallets[WHERE removed == NONE].address CONTAINS $address",
+        )
+        .bind(("address", address))
+        .await
+        .unwrap();
+    let user_data: Option<User> = res.take(0).unwrap();
+
+    let Some(user_data) = user_data else {
+        return (StatusCode::NOT_FOUND, "no user linked to wallet");
+    };
+
+    debug!("siwe login for {}", user_data.username);
+
+    auth.login(&AuthUser {
+        id: user_data.username,
+    })
+    .await
+    .unwrap();
+
+    (StatusCode::OK, "ok")
+}
+
 /*
 #[derive(Debug, Deserialize)]
 struct RegisterBody {
@@ -1,18 +1,29 @@
-use std::collections::HashMap;
-
 use axum::{
     extract::{Json, Path, Query, State},
     response::{Html, IntoResponse},
     routing::{get, post},
     Extension, Router,
 };
-use log::debug;
+use base64::{engine::general_purpose, Engine as _};
+use gitbounties_contract::{
+    account_balance, erc20_decimals, erc20_symbol, token_escrow, token_owner, ChainConfig,
+    Middleware, U256,
+};
+use log::{debug, error};
 use reqwest::{Method, StatusCode};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::Thing;
 
-use super::github::{get_installation, get_installation_access_token};
+use super::{
+    github::{get_installation, get_installation_access_token},
+    mint::chain_error,
+};
 use crate::{
-    models::{Bounty, BountyStatus, Issue, User},
+    amount::{parse_asset_token, reward_key},
+    models::{Address, Amount, Asset, Bounty, BountyStatus, ChainEvent, Issue, User},
+    notify::{notify_bounty_event, BountyEvent},
+    payout::refund_bounty,
+    search::Highlights,
     session_auth::{AuthUser, MyRequireAuthorizationLayer},
     AppState,
 };
@@ -24,13 +35,29 @@ pub fn router() -> Router<AppState> {
             post(create).layer(MyRequireAuthorizationLayer::login()),
         )
         .route("/", get(list).layer(MyRequireAuthorizationLayer::login()))
+        .route(
+            "/:id/cancel",
+            post(cancel).layer(MyRequireAuthorizationLayer::login()),
+        )
+        .route(
+            "/:id/onchain",
+            get(onchain).layer(MyRequireAuthorizationLayer::login()),
+        )
+        .route(
+            "/search",
+            get(search).layer(MyRequireAuthorizationLayer::login()),
+        )
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CreateBody {
-    /// Value of the reward
-    pub reward: u64,
+    /// Value of the reward, in the smallest unit of the reward asset
+    pub reward: Amount,
+    /// ERC-20 token the reward is paid in, leave empty for native ETH
+    pub reward_token: Option<Address>,
     pub token_id: u64,
+    /// Chain the token was minted on, defaults to the default chain
+    pub chain_id: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -49,21 +76,68 @@ pub async fn create(
 ) -> (StatusCode, String) {
     // NOTE shoud we check that the user is owner of the issue to monetize it?
 
-    //debug!("jwt {}", state.github_jwt);
+    let (installation_access_token, user_data) =
+        match authorize_issue(&state, &auth_user.id, &query).await {
+            Ok(res) => res,
+            Err(err) => return err,
+        };
+
+    let chain = match select_chain(&state, payload.chain_id) {
+        Ok(chain) => chain,
+        Err(err) => return err,
+    };
+
+    // make sure the user actually funded the token they are attaching
+    let wallets = user_wallets(&user_data);
+    let token = match verify_token(
+        &state,
+        chain,
+        &wallets,
+        payload.token_id,
+        payload.reward,
+        payload.reward_token,
+    )
+    .await
+    {
+        Ok(token) => token,
+        Err(err) => return err,
+    };
 
+    match insert_bounty(
+        &state,
+        &auth_user.id,
+        &query,
+        &installation_access_token,
+        chain,
+        token,
+    )
+    .await
+    {
+        Ok(_) => (StatusCode::OK, "Ok".into()),
+        Err(err) => err,
+    }
+}
+
+/// Check that the user manages the app installation of the issue's repository, returning an
+/// installation access token and the user
+pub(crate) async fn authorize_issue(
+    state: &AppState,
+    username: &str,
+    query: &IssueQuery,
+) -> Result<(String, User), (StatusCode, String)> {
     // auth process as referenced here
     // https://docs.github.com/en/apps/creating-github-apps/authenticating-with-a-github-app/authenticating-as-a-github-app-installation
 
     // get the installation id
-    let Some(installation_id) = get_installation(&state, &query.owner, &query.repo).await else {
-        return (StatusCode::NOT_FOUND, "Invalid issue".into());
+    let Some(installation_id) = get_installation(state, &query.owner, &query.repo).await else {
+        return Err((StatusCode::NOT_FOUND, "Invalid issue".into()));
     };
-    let installation_access_token = get_installation_access_token(&state, installation_id).await;
+    let installation_access_token = get_installation_access_token(state, installation_id).await;
 
     // Check if user has permission to manage this installation
     let user_data: User = state
         .db_conn
-        .select(("Users", &auth_user.id))
+        .select(("Users", username))
         .await
         .expect("User should exist in database");
 
@@ -76,12 +150,73 @@ pub async fn create(
         .github_installations
         .contains(&(installation_id as usize))
     {
-        return (
+        return Err((
             StatusCode::FORBIDDEN,
             "No permission to manage installation".into(),
-        );
+        ));
     }
 
+    Ok((installation_access_token, user_data))
+}
+
+/// Whether the user may manage the app installation on a repository
+pub(crate) async fn manages_repo(
+    state: &AppState,
+    username: &str,
+    owner: &str,
+    repo: &str,
+) -> bool {
+    let Some(installation_id) = get_installation(state, owner, repo).await else {
+        return false;
+    };
+    let user_data: Option<User> = state.db_conn.select(("Users", username)).await.unwrap();
+
+    user_data.is_some_and(|user| {
+        user.github_installations
+            .contains(&(installation_id as usize))
+    })
+}
+
+/// Configured chain a request asked for, or the default chain
+pub(crate) fn select_chain(
+    state: &AppState,
+    chain_id: Option<u64>,
+) -> Result<&ChainConfig, (StatusCode, String)> {
+    state
+        .chains
+        .get(chain_id)
+        .ok_or((StatusCode::BAD_REQUEST, "Unsupported chain".into()))
+}
+
+/// Chain the token of a bounty lives on, failing if it is no longer configured
+pub(crate) fn bounty_chain(
+    state: &AppState,
+    bounty: &Bounty,
+) -> Result<ChainConfig, (StatusCode, String)> {
+    state.chains.for_bounty(bounty).ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Chain of the bounty is no longer supported".into(),
+    ))
+}
+
+/// Wallets the user can hold bounty tokens in
+pub(crate) fn user_wallets(user_data: &User) -> Vec<Address> {
+    let mut wallets = vec![user_data.payout_wallet()];
+    wallets.extend(user_data.active_wallets().map(|wallet| wallet.address));
+    wallets
+}
+
+/// Store a bounty for an issue, backed by a token that has already been verified
+pub(crate) async fn insert_bounty(
+    state: &AppState,
+    username: &str,
+    query: &IssueQuery,
+    installation_access_token: &str,
+    chain: &ChainConfig,
+    token: VerifiedToken,
+) -> Result<Bounty, (StatusCode, String)> {
+    let asset = reward_asset(chain, token.reward_token).await?;
+
     // fetch info about the issue
     // TODO convert to graphql?
     let res = state
@@ -91,7 +226,7 @@ pub async fn create(
                 "https://api.github.com/repos/{}/{}/issues/{}",
                 query.owner, query.repo, query.issue
             ),
-            &installation_access_token,
+            installation_access_token,
         )
         .send()
         .await
@@ -99,22 +234,38 @@ pub async fn create(
 
     if !res.status().is_success() {
         let body = res.text().await.unwrap();
-        // TODO return proper response
-        panic!("Issue does not exist {}", body);
+        debug!("Issue does not exist {}", body);
+        return Err((StatusCode::NOT_FOUND, "Issue does not exist".into()));
     }
 
     let body = res.json::<serde_json::Value>().await.unwrap();
 
     // debug!("issue {}", body);
 
+    // fetch the repository to know if the bounty can be listed publicly
+    let res = state
+        .reqwest_github(
+            Method::GET,
+            &format!(
+                "https://api.github.com/repos/{}/{}",
+                query.owner, query.repo
+            ),
+            installation_access_token,
+        )
+        .send()
+        .await
+        .unwrap();
+    let repo_body = res.json::<serde_json::Value>().await.unwrap();
+    let private = repo_body["private"].as_bool().unwrap_or(true);
+
     // Open issue as new bounty
-    // TODO throw warning if already registered
-    let res: Bounty = state
+    let res: surrealdb::Result<Bounty> = state
         .db_conn
         .create("Bounty")
         .content(Bounty {
-            user: auth_user.id,
-            reward: payload.reward,
+            id: None,
+            user: username.to_string(),
+            reward: token.reward,
             status: BountyStatus::Open,
             issue: Issue {
                 owner: query.owner.clone(),
@@ -122,7 +273,7 @@ pub async fn create(
                 issue_id: body["number"].as_u64().unwrap() as usize,
             },
             title: body["title"].as_str().unwrap().into(),
-            description: body["body"].as_str().unwrap().into(), // TODO body may not exist
+            description: body["body"].as_str().unwrap_or_default().into(),
             labels: body["labels"]
                 .as_array()
                 .unwrap()
@@ -130,38 +281,788 @@ pub async fn create(
                 .map(|label| label["name"].as_str().unwrap().to_string())
                 .collect(),
             created: chrono::offset::Utc::now(),
-            token_id: payload.token_id,
+            token_id: token.token_id,
+            private,
+            comment_id: None,
+            label: None,
+            issue_deleted: false,
+            frozen: false,
+            chain_id: chain.chain_id,
+            contract_address: chain.contract_address,
+            reward_key: reward_key(asset.token, token.reward),
+            asset,
+            escrow_balance: Some(token.balance),
+            custodial: token.custodial,
         })
+        .await;
+    let res = match res {
+        Ok(res) => res,
+        // the unique token index rejects a concurrent request attaching the same token
+        Err(_)
+            if bounty_for_token(state, chain, token.token_id)
+                .await
+                .is_some() =>
+        {
+            return Err((
+                StatusCode::CONFLICT,
+                "Token is already attached to a bounty".into(),
+            ));
+        },
+        Err(err) => {
+            error!("Failed to create bounty: {err}");
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to create bounty".into(),
+            ));
+        },
+    };
+
+    state.search.write().await.insert_bounty(&res);
+
+    // Send notification on the original issue to mark it as a bounty
+    notify_bounty_event(state, &res, BountyEvent::Created).await;
+
+    Ok(res)
+}
+
+/// Make sure the reward is paid in native ETH or an ERC-20 token allowed on the chain, since
+/// anything answering like a token could otherwise pose as a well known one
+pub(crate) fn check_reward_token(
+    chain: &ChainConfig,
+    reward_token: Option<Address>,
+) -> Result<(), (StatusCode, String)> {
+    match reward_token {
+        Some(reward_token) if !chain.reward_tokens.contains(&reward_token) => Err((
+            StatusCode::BAD_REQUEST,
+            "Reward token is not accepted on this chain".into(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Describe the asset the reward is paid in, native ETH when no token is given
+async fn reward_asset(
+    chain: &ChainConfig,
+    reward_token: Option<Address>,
+) -> Result<Asset, (StatusCode, String)> {
+    let Some(reward_token) = reward_token else {
+        return Ok(Asset::native());
+    };
+
+    let contract = chain
+        .read_only_contract()
+        .expect("Coudln't initalize contract");
+    let decimals = erc20_decimals(&contract, reward_token)
+        .await
+        .map_err(|err| {
+            debug!("failed to read decimals of {reward_token:?}: {err}");
+            (
+                StatusCode::BAD_REQUEST,
+                "Reward token is not an ERC-20 token".into(),
+            )
+        })?;
+    // symbol is optional in ERC-20, don't reject tokens without one
+    let symbol = erc20_symbol(&contract, reward_token)
+        .await
+        .unwrap_or_else(|_| "tokens".to_string());
+
+    Ok(Asset {
+        token: Some(reward_token),
+        decimals,
+        symbol,
+    })
+}
+
+/// Token that passed `verify_token`, with the reward it backs
+#[derive(Debug)]
+pub(crate) struct VerifiedToken {
+    pub token_id: u64,
+    pub reward: Amount,
+    pub reward_token: Option<Address>,
+    /// Balance of the reward asset held by the token bound account when it was verified
+    pub balance: Amount,
+    /// Whether the operator funded the token on behalf of the organization
+    pub custodial: bool,
+}
+
+/// Bounty backed by a token, if any
+async fn bounty_for_token(state: &AppState, chain: &ChainConfig, token_id: u64) -> Option<Bounty> {
+    let mut res = state
+        .db_conn
+        .query("SELECT * FROM Bounty WHERE token_id == $token_id AND chain_id == $chain_id AND contract_address == $contract_address")
+        .bind(("token_id", token_id))
+        .bind(("chain_id", chain.chain_id))
+        .bind(("contract_address", chain.contract_address))
         .await
         .unwrap();
+    res.take(0).unwrap()
+}
 
-    // generate smart contract
+/// Check that the bounty token is held by one of `owners`, holds at least the reward in an
+/// accepted reward asset and isn't already backing another bounty
+pub(crate) async fn verify_token(
+    state: &AppState,
+    chain: &ChainConfig,
+    owners: &[Address],
+    token_id: u64,
+    reward: Amount,
+    reward_token: Option<Address>,
+) -> Result<VerifiedToken, (StatusCode, String)> {
+    check_reward_token(chain, reward_token)?;
 
-    // Send notification on the original issue to mark it as a bounty
+    if bounty_for_token(state, chain, token_id).await.is_some() {
+        return Err((
+            StatusCode::CONFLICT,
+            "Token is already attached to a bounty".into(),
+        ));
+    }
+
+    let contract = chain
+        .read_only_contract()
+        .expect("Coudln't initalize contract");
+
+    let escrow = match token_escrow(&contract, token_id.into(), reward_token).await {
+        Ok(escrow) => escrow,
+        Err(err) => {
+            debug!("failed to look up token {token_id}: {err}");
+            return Err((StatusCode::BAD_REQUEST, "Token does not exist".into()));
+        },
+    };
+
+    if !owners.contains(&escrow.owner) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Token is not owned by one of your wallets".into(),
+        ));
+    }
+
+    if escrow.balance < reward.0 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Token account {:?} holds {} which is less than the reward",
+                escrow.account, escrow.balance
+            ),
+        ));
+    }
+
+    Ok(VerifiedToken {
+        token_id,
+        reward,
+        reward_token,
+        balance: Amount(escrow.balance),
+        custodial: false,
+    })
+}
+
+/// Cancel an open bounty, refunding the reward to its owner, or to the operator if it funded it
+pub async fn cancel(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Extension(auth_user): Extension<AuthUser>,
+) -> (StatusCode, String) {
+    let bounty: Option<Bounty> = state.db_conn.select(("Bounty", id.as_str())).await.unwrap();
+
+    let Some(bounty) = bounty else {
+        return (StatusCode::NOT_FOUND, "Bounty not found".into());
+    };
+    if bounty.user != auth_user.id {
+        return (StatusCode::FORBIDDEN, "Not the owner of the bounty".into());
+    }
+    if bounty.status != BountyStatus::Open {
+        return (StatusCode::CONFLICT, "Bounty is not open".into());
+    }
+    if let Err(err) = bounty_chain(&state, &bounty) {
+        return err;
+    }
+
+    if !refund_bounty(&state, &bounty).await {
+        return (
+            StatusCode::CONFLICT,
+            "Bounty funds are already being released".into(),
+        );
+    }
+    notify_bounty_event(&state, &bounty, BountyEvent::Cancelled).await;
 
     (StatusCode::OK, "Ok".into())
 }
 
-/// Get all created bounties for user
+/// Number of recent chain events returned with the on-chain state of a bounty
+const RECENT_EVENTS: usize = 20;
+
+/// Balance of one asset held by a token bound account
+#[derive(Debug, Serialize)]
+pub struct AccountBalance {
+    pub asset: Asset,
+    pub balance: Amount,
+}
+
+/// Live state of the token backing a bounty, read from the chain
+#[derive(Debug, Serialize)]
+pub struct OnchainState {
+    pub chain_id: u64,
+    pub contract_address: Address,
+    pub token_id: u64,
+    /// Current owner of the token, `None` once it has been burned
+    pub owner: Option<Address>,
+    /// ERC-6551 registry and account implementation the token bound account is derived from
+    pub registry: Address,
+    pub implementation: Address,
+    /// Token bound account holding the funds
+    pub account: Address,
+    /// Whether the account contract was created yet, funds can be sent to it before
+    pub account_deployed: bool,
+    /// Native balance, followed by the reward token balance for ERC-20 rewards
+    pub balances: Vec<AccountBalance>,
+    /// Metadata uri of the token, `None` once it has been burned
+    pub token_uri: Option<String>,
+    /// Latest confirmed events the indexer recorded on the token, newest first. Deposits are
+    /// detected from balance changes, so they carry the block they were seen at but no
+    /// transaction hash.
+    pub indexed_events: Vec<ChainEvent>,
+}
+
+/// Where the funds of a bounty actually sit. Bounties on public repositories are also served
+/// under `/public`, this route lets the owner of a private bounty see it.
+pub async fn onchain(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Extension(auth_user): Extension<AuthUser>,
+) -> Result<Json<OnchainState>, (StatusCode, String)> {
+    let bounty: Option<Bounty> = state.db_conn.select(("Bounty", id.as_str())).await.unwrap();
+    let Some(bounty) = bounty else {
+        return Err((StatusCode::NOT_FOUND, "Bounty not found".into()));
+    };
+    if !visible_to(&bounty, &auth_user.id) {
+        return Err((StatusCode::NOT_FOUND, "Bounty not found".into()));
+    }
+
+    onchain_state(&state, &bounty).await.map(Json)
+}
+
+/// Private bounties are only shown to their owner
+fn visible_to(bounty: &Bounty, username: &str) -> bool {
+    !bounty.private || bounty.user == username
+}
+
+/// Read the live state of the token backing a bounty, so the escrow can be verified by anyone
+pub(crate) async fn onchain_state(
+    state: &AppState,
+    bounty: &Bounty,
+) -> Result<OnchainState, (StatusCode, String)> {
+    let chain = bounty_chain(state, bounty)?;
+    let contract = chain
+        .read_only_contract()
+        .expect("Coudln't initalize contract");
+    let token_id = U256::from(bounty.token_id);
+
+    let owner = token_owner(&contract, token_id)
+        .await
+        .map_err(chain_error)?;
+    let registry = contract.registry().call().await.map_err(chain_error)?;
+    let implementation = contract
+        .implementation()
+        .call()
+        .await
+        .map_err(chain_error)?;
+    // the account address is derived from the token, so it is known even after the burn
+    let account = contract
+        .get_account(token_id)
+        .call()
+        .await
+        .map_err(chain_error)?;
+    let code = contract
+        .client()
+        .get_code(account, None)
+        .await
+        .map_err(chain_error)?;
+
+    let mut balances = vec![AccountBalance {
+        asset: Asset::native(),
+        balance: Amount(
+            account_balance(&contract, account, None, None)
+                .await
+                .map_err(chain_error)?,
+        ),
+    }];
+    if bounty.asset.token.is_some() {
+        balances.push(AccountBalance {
+            balance: Amount(
+                account_balance(&contract, account, bounty.asset.token, None)
+                    .await
+                    .map_err(chain_error)?,
+            ),
+            asset: bounty.asset.clone(),
+        });
+    }
+
+    let token_uri = match owner {
+        Some(_) => Some(
+            contract
+                .token_uri(token_id)
+                .call()
+                .await
+                .map_err(chain_error)?,
+        ),
+        None => None,
+    };
+
+    let mut res = state
+        .db_conn
+        // events recorded before the contract was tracked have none
+        .query(format!("SELECT * FROM ChainEvents WHERE chain_id == $chain_id AND contract_address INSIDE [$contract_address, NONE] AND token_id == $token_id ORDER BY block DESC LIMIT {RECENT_EVENTS}"))
+        .bind(("chain_id", bounty.chain_id))
+        .bind(("contract_address", bounty.contract_address))
+        .bind(("token_id", bounty.token_id))
+        .await
+        .unwrap();
+    let indexed_events: Vec<ChainEvent> = res.take(0).unwrap();
+
+    Ok(OnchainState {
+        chain_id: bounty.chain_id,
+        contract_address: bounty.contract_address,
+        token_id: bounty.token_id,
+        owner,
+        registry,
+        implementation,
+        account,
+        account_deployed: !code.is_empty(),
+        balances,
+        token_uri,
+        indexed_events,
+    })
+}
+
+/// Default number of bounties returned per page
+const DEFAULT_PAGE_SIZE: usize = 20;
+/// Upper bound on the page size a client can request
+const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortField {
+    #[default]
+    Created,
+    Reward,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct ListQuery {
+    /// Only return bounties created by the logged in user
+    #[serde(default)]
+    pub user: bool,
+    pub status: Option<BountyStatus>,
+    pub owner: Option<String>,
+    pub repo: Option<String>,
+    /// Comma separated list of labels, bounty must have all of them
+    pub labels: Option<String>,
+    /// Asset to list rewards in, `ETH` or an ERC-20 token address. Required to filter or sort on
+    /// rewards, since amounts of different assets don't compare.
+    pub asset: Option<String>,
+    pub min_reward: Option<Amount>,
+    pub max_reward: Option<Amount>,
+    pub created_after: Option<chrono::DateTime<chrono::offset::Utc>>,
+    pub created_before: Option<chrono::DateTime<chrono::offset::Utc>>,
+    #[serde(default)]
+    pub sort: SortField,
+    #[serde(default)]
+    pub order: SortOrder,
+    pub limit: Option<usize>,
+    /// Opaque token returned as `next_page` by the previous request
+    pub page: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BountyPage {
+    pub bounties: Vec<Bounty>,
+    /// Token to fetch the following page, missing if this is the last page
+    pub next_page: Option<String>,
+}
+
+/// Position of the last bounty in a page.
+///
+/// Encodes the sort key along with the record id so that bounties sharing the same sort key are
+/// still paged through in a stable order.
+#[derive(Debug, Serialize, Deserialize)]
+struct PageCursor {
+    sort: SortField,
+    created: chrono::DateTime<chrono::offset::Utc>,
+    reward_key: String,
+    id: String,
+}
+
+impl PageCursor {
+    fn from_bounty(sort: SortField, bounty: &Bounty) -> Option<Self> {
+        Some(PageCursor {
+            sort,
+            created: bounty.created,
+            reward_key: bounty.reward_key.clone(),
+            id: bounty.id.as_ref()?.id.to_raw(),
+        })
+    }
+
+    fn encode(&self) -> String {
+        let raw = serde_json::to_vec(self).expect("Couldn't serialize page cursor");
+        general_purpose::URL_SAFE_NO_PAD.encode(raw)
+    }
+
+    fn decode(token: &str) -> Option<Self> {
+        let raw = general_purpose::URL_SAFE_NO_PAD.decode(token).ok()?;
+        serde_json::from_slice(&raw).ok()
+    }
+}
+
+/// Column to sort on, the comparison selecting rows after the cursor and the sort direction.
+///
+/// Pagination is keyset based: a page continues strictly after the last seen (sort key, id) pair.
+fn keyset_order(sort: SortField, order: SortOrder) -> (&'static str, &'static str, &'static str) {
+    match (sort, order) {
+        (SortField::Created, SortOrder::Asc) => ("created", ">", "ASC"),
+        (SortField::Created, SortOrder::Desc) => ("created", "<", "DESC"),
+        (SortField::Reward, SortOrder::Asc) => ("reward_key", ">", "ASC"),
+        (SortField::Reward, SortOrder::Desc) => ("reward_key", "<", "DESC"),
+    }
+}
+
+/// Get created bounties, optionally filtered, sorted and paginated
 pub async fn list(
     State(state): State<AppState>,
-    Query(params): Query<HashMap<String, String>>,
+    Query(params): Query<ListQuery>,
     Extension(auth_user): Extension<AuthUser>,
-) -> Json<Vec<Bounty>> {
-    let mut res = if params.get("user") == Some(&String::from("true")) {
-        state
+) -> Result<Json<BountyPage>, (StatusCode, String)> {
+    let user = params.user.then_some(auth_user.id);
+    query_bounties(&state, params, user, false).await.map(Json)
+}
+
+/// Run a filtered and paginated bounty query.
+///
+/// If `user` is given only that user's bounties are returned. `public_only` restricts results to
+/// open bounties on public repositories whose issue still exists.
+pub async fn query_bounties(
+    state: &AppState,
+    params: ListQuery,
+    user: Option<String>,
+    public_only: bool,
+) -> Result<BountyPage, (StatusCode, String)> {
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    let cursor = match &params.page {
+        Some(token) => match PageCursor::decode(token) {
+            Some(cursor) if cursor.sort == params.sort => Some(cursor),
+            _ => return Err((StatusCode::BAD_REQUEST, "Invalid page token".into())),
+        },
+        None => None,
+    };
+
+    let mut conditions: Vec<&str> = vec![];
+    if user.is_some() {
+        conditions.push("user == $user");
+    }
+    if public_only {
+        conditions.push("status == 'Open'");
+        conditions.push("private == false");
+        conditions.push("issue_deleted != true");
+        conditions.push("frozen != true");
+    }
+    if params.status.is_some() {
+        conditions.push("status == $status");
+    }
+    if params.owner.is_some() {
+        conditions.push("issue.owner == $owner");
+    }
+    if params.repo.is_some() {
+        conditions.push("issue.repo == $repo");
+    }
+    let labels = params
+        .labels
+        .as_deref()
+        .map(|labels| {
+            labels
+                .split(',')
+                .map(|label| label.trim().to_string())
+                .filter(|label| !label.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    if !labels.is_empty() {
+        conditions.push("labels CONTAINSALL $labels");
+    }
+    let asset_token = params
+        .asset
+        .as_deref()
+        .map(parse_asset_token)
+        .transpose()
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    let uses_reward = params.min_reward.is_some()
+        || params.max_reward.is_some()
+        || params.sort == SortField::Reward;
+    if uses_reward && asset_token.is_none() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "asset is required to filter or sort on rewards".into(),
+        ));
+    }
+    // reward keys of one asset share a prefix and compare exactly, so this also limits results to
+    // the asset
+    let reward_range = asset_token.map(|token| {
+        (
+            reward_key(token, params.min_reward.unwrap_or_default()),
+            reward_key(token, params.max_reward.unwrap_or(Amount(U256::MAX))),
+        )
+    });
+    if reward_range.is_some() {
+        conditions.push("reward_key >= $min_reward_key");
+        conditions.push("reward_key <= $max_reward_key");
+    }
+    let (min_reward_key, max_reward_key) = reward_range.unzip();
+    if params.created_after.is_some() {
+        conditions.push("created >= $created_after");
+    }
+    if params.created_before.is_some() {
+        conditions.push("created <= $created_before");
+    }
+
+    let (sort_column, cmp, dir) = keyset_order(params.sort, params.order);
+    let cursor_condition = format!(
+        "({sort_column} {cmp} $cursor_key OR ({sort_column} == $cursor_key AND id {cmp} $cursor_id))"
+    );
+    if cursor.is_some() {
+        conditions.push(&cursor_condition);
+    }
+
+    let mut sql = String::from("SELECT * FROM Bounty");
+    if !conditions.is_empty() {
+        sql.push_str(" WHERE ");
+        sql.push_str(&conditions.join(" AND "));
+    }
+    // fetch one extra row to know if there is another page
+    sql.push_str(&format!(
+        " ORDER BY {sort_column} {dir}, id {dir} LIMIT {}",
+        limit + 1
+    ));
+
+    debug!("bounty list query {sql}");
+
+    let mut query = state
+        .db_conn
+        .query(sql)
+        .bind(("user", user))
+        .bind(("status", params.status))
+        .bind(("owner", params.owner))
+        .bind(("repo", params.repo))
+        .bind(("labels", labels))
+        .bind(("min_reward_key", min_reward_key))
+        .bind(("max_reward_key", max_reward_key))
+        .bind(("created_after", params.created_after))
+        .bind(("created_before", params.created_before));
+
+    if let Some(cursor) = &cursor {
+        query = match params.sort {
+            SortField::Created => query.bind(("cursor_key", cursor.created)),
+            SortField::Reward => query.bind(("cursor_key", &cursor.reward_key)),
+        };
+        query = query.bind(("cursor_id", Thing::from(("Bounty", cursor.id.as_str()))));
+    }
+
+    let mut res = query.await.unwrap();
+    let mut bounties: Vec<Bounty> = res.take(0).unwrap();
+
+    let next_page = if bounties.len() > limit {
+        bounties.truncate(limit);
+        bounties
+            .last()
+            .and_then(|bounty| PageCursor::from_bounty(params.sort, bounty))
+            .map(|cursor| cursor.encode())
+    } else {
+        None
+    };
+
+    debug!("user bounties {:?}", bounties);
+
+    Ok(BountyPage {
+        bounties,
+        next_page,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResult {
+    pub bounty: Bounty,
+    pub score: f32,
+    pub highlights: Highlights,
+}
+
+/// Full-text search over bounty titles, descriptions and labels, best match first. Bounties on
+/// private repositories only show up for their owner.
+pub async fn search(
+    State(state): State<AppState>,
+    Query(params): Query<SearchQuery>,
+    Extension(auth_user): Extension<AuthUser>,
+) -> Json<Vec<SearchResult>> {
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    let hits = state
+        .search
+        .read()
+        .await
+        .search(&params.q, &auth_user.id, limit);
+
+    let mut results = vec![];
+    for hit in hits {
+        let bounty: Option<Bounty> = state
             .db_conn
-            .query("SELECT * FROM Bounty WHERE user == $user")
-            .bind(("user", auth_user.id))
+            .select(("Bounty", hit.id.as_str()))
             .await
-            .unwrap()
-    } else {
-        state.db_conn.query("SELECT * FROM Bounty").await.unwrap()
+            .unwrap();
+        // index may briefly lag behind the database
+        let Some(bounty) = bounty else {
+            continue;
+        };
+        if !visible_to(&bounty, &auth_user.id) {
+            continue;
+        }
+        results.push(SearchResult {
+            bounty,
+            score: hit.score,
+            highlights: hit.highlights,
+        });
+    }
+
+    debug!("search '{}' returned {} results", params.q, results.len());
+
+    Json(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{keyset_order, visible_to, OnchainState, PageCursor, SortField, SortOrder};
+    use crate::{
+        amount::reward_key,
+        models::{Address, Amount, Bounty, ChainEvent, ChainEventKind},
     };
 
-    let bounties: Vec<Bounty> = res.take(0).unwrap();
+    fn cursor(sort: SortField) -> PageCursor {
+        PageCursor {
+            sort,
+            created: "2023-05-01T12:00:00Z".parse().unwrap(),
+            reward_key: reward_key(None, Amount::from(15)),
+            id: "abc123".into(),
+        }
+    }
 
-    debug!("user bounties {:?}", bounties);
+    #[test]
+    fn page_cursor_round_trips() {
+        let encoded = cursor(SortField::Reward).encode();
+        // tokens end up in query strings
+        assert!(encoded
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+
+        let decoded = PageCursor::decode(&encoded).unwrap();
+        assert_eq!(decoded.sort, SortField::Reward);
+        assert_eq!(decoded.created, cursor(SortField::Reward).created);
+        assert_eq!(decoded.reward_key, cursor(SortField::Reward).reward_key);
+        assert_eq!(decoded.id, "abc123");
+    }
+
+    #[test]
+    fn page_cursor_rejects_invalid_tokens() {
+        assert!(PageCursor::decode("").is_none());
+        assert!(PageCursor::decode("not a cursor").is_none());
+        // valid base64 that isn't a cursor
+        assert!(PageCursor::decode("e30").is_none());
 
-    Json(bounties)
+        let mut encoded = cursor(SortField::Created).encode();
+        encoded.truncate(encoded.len() / 2);
+        assert!(PageCursor::decode(&encoded).is_none());
+    }
+
+    #[test]
+    fn keyset_continues_in_sort_direction() {
+        assert_eq!(
+            keyset_order(SortField::Created, SortOrder::Asc),
+            ("created", ">", "ASC")
+        );
+        assert_eq!(
+            keyset_order(SortField::Created, SortOrder::Desc),
+            ("created", "<", "DESC")
+        );
+        // rewards are sorted on the fixed width key, so amounts compare exactly
+        assert_eq!(
+            keyset_order(SortField::Reward, SortOrder::Asc),
+            ("reward_key", ">", "ASC")
+        );
+        assert_eq!(
+            keyset_order(SortField::Reward, SortOrder::Desc),
+            ("reward_key", "<", "DESC")
+        );
+    }
+
+    #[test]
+    fn private_bounties_are_only_visible_to_their_owner() {
+        let public = Bounty::example();
+        assert!(visible_to(&public, "alice"));
+        assert!(visible_to(&public, "bob"));
+
+        let private = Bounty {
+            private: true,
+            ..Bounty::example()
+        };
+        assert!(visible_to(&private, "alice"));
+        assert!(!visible_to(&private, "bob"));
+    }
+
+    #[test]
+    fn burned_tokens_report_no_owner_and_deposits_no_transaction() {
+        let state = OnchainState {
+            chain_id: 1,
+            contract_address: Address::repeat_byte(0xc0),
+            token_id: 7,
+            owner: None,
+            registry: Address::repeat_byte(0xe6),
+            implementation: Address::repeat_byte(0x1a),
+            account: Address::repeat_byte(0xac),
+            account_deployed: true,
+            balances: vec![],
+            token_uri: None,
+            indexed_events: vec![ChainEvent {
+                kind: ChainEventKind::Deposit,
+                chain_id: 1,
+                contract_address: Address::repeat_byte(0xc0),
+                token_id: 7,
+                from: Address::zero(),
+                to: Address::repeat_byte(0xac),
+                amount: Some(Amount::from(5)),
+                block: 100,
+                tx_hash: None,
+            }],
+        };
+
+        let json = serde_json::to_value(&state).unwrap();
+        assert!(json["owner"].is_null());
+        assert!(json["token_uri"].is_null());
+        assert_eq!(json["indexed_events"][0]["kind"], "Deposit");
+        assert_eq!(json["indexed_events"][0]["amount"], "5");
+        assert!(json["indexed_events"][0]["tx_hash"].is_null());
+    }
 }
@@ -0,0 +1,207 @@
+//! Track which accounts and repositories the github app has access to
+
+use log::{debug, info};
+
+use crate::{models::Installation, AppState};
+
+/// Names of the repositories listed under `field` in a webhook payload
+fn repository_names(payload: &serde_json::Value, field: &str) -> Vec<String> {
+    payload[field]
+        .as_array()
+        .map(|repos| {
+            repos
+                .iter()
+                .map(|repo| repo["name"].as_str().unwrap().to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Handle the `installation` webhook event
+pub async fn installation_webhook(state: &AppState, action: &str, payload: &serde_json::Value) {
+    let installation_raw = &payload["installation"];
+    let installation_id = installation_raw["id"]
+        .as_u64()
+        .expect("Couldn't get installation id");
+    let account = installation_raw["account"]["login"]
+        .as_str()
+        .expect("Couldn't get installation account");
+
+    match action {
+        "created" => {
+            let installation = Installation {
+                account: account.to_string(),
+                all_repositories: installation_raw["repository_selection"] == "all",
+                repositories: repository_names(payload, "repositories"),
+                suspended: false,
+                updated: chrono::offset::Utc::now(),
+            };
+            // users are only given access to the installation from the install callback, which
+            // asks github which installations they can manage
+            save_installation(state, installation_id, &installation).await;
+            set_frozen(state, &installation, None, false).await;
+        },
+        "deleted" => {
+            state
+                .db_conn
+                .query("DELETE type::thing('Installations', $installation)")
+                .bind(("installation", installation_id))
+                .await
+                .unwrap();
+
+            state
+                .db_conn
+                .query("UPDATE Users SET github_installations -= $installation WHERE github_installations CONTAINS $installation")
+                .bind(("installation", installation_id))
+                .await
+                .unwrap();
+
+            freeze_account(state, account).await;
+        },
+        "suspend" | "unsuspend" => {
+            let suspended = action == "suspend";
+            let mut res = state
+                .db_conn
+                .query("UPDATE type::thing('Installations', $installation) SET suspended = $suspended, updated = $now RETURN AFTER")
+                .bind(("installation", installation_id))
+                .bind(("suspended", suspended))
+                .bind(("now", chrono::offset::Utc::now()))
+                .await
+                .unwrap();
+            let installation: Option<Installation> = res.take(0).unwrap();
+
+            match installation {
+                Some(installation) if !suspended => {
+                    set_frozen(state, &installation, None, false).await
+                },
+                _ => freeze_account(state, account).await,
+            }
+        },
+        _ => {
+            debug!("Unhandled installation action {action}");
+            return;
+        },
+    }
+
+    info!("installation {installation_id} on {account} {action}");
+}
+
+/// Handle the `installation_repositories` webhook event
+pub async fn installation_repositories_webhook(
+    state: &AppState,
+    action: &str,
+    payload: &serde_json::Value,
+) {
+    let installation_id = payload["installation"]["id"]
+        .as_u64()
+        .expect("Couldn't get installation id");
+
+    let installation: Option<Installation> = state
+        .db_conn
+        .select(("Installations", installation_id as i64))
+        .await
+        .unwrap();
+    // installations made before they were tracked are picked up from the payload, their list of
+    // repositories only covers the changes seen since
+    let mut installation = installation.unwrap_or_else(|| {
+        debug!("installation {installation_id} was not tracked yet");
+        Installation {
+            account: payload["installation"]["account"]["login"]
+                .as_str()
+                .expect("Couldn't get installation account")
+                .to_string(),
+            all_repositories: false,
+            repositories: vec![],
+            suspended: false,
+            updated: chrono::offset::Utc::now(),
+        }
+    });
+
+    installation.all_repositories = payload["repository_selection"] == "all";
+    let (changed, frozen) = match action {
+        "added" => {
+            let added = repository_names(payload, "repositories_added");
+            for repo in added.iter() {
+                if !installation.repositories.contains(repo) {
+                    installation.repositories.push(repo.clone());
+                }
+            }
+            (added, false)
+        },
+        "removed" => {
+            let removed = repository_names(payload, "repositories_removed");
+            installation
+                .repositories
+                .retain(|repo| !removed.contains(repo));
+            (removed, true)
+        },
+        _ => {
+            debug!("Unhandled installation_repositories action {action}");
+            return;
+        },
+    };
+    installation.updated = chrono::offset::Utc::now();
+
+    save_installation(state, installation_id, &installation).await;
+    if !installation.suspended {
+        set_frozen(state, &installation, Some(&changed), frozen).await;
+    }
+
+    info!(
+        "repositories {:?} {} for installation {}",
+        changed, action, installation_id
+    );
+}
+
+async fn save_installation(state: &AppState, installation_id: u64, installation: &Installation) {
+    state
+        .db_conn
+        .query("UPDATE type::thing('Installations', $installation) CONTENT $content")
+        .bind(("installation", installation_id))
+        .bind(("content", installation))
+        .await
+        .unwrap();
+}
+
+/// Freeze or unfreeze the unfinished bounties on the repositories of an installation
+///
+/// When `repos` is given only bounties on those repositories are touched. Unfreezing is limited to
+/// repositories the installation still has access to.
+async fn set_frozen(
+    state: &AppState,
+    installation: &Installation,
+    repos: Option<&[String]>,
+    frozen: bool,
+) {
+    let mut conditions = vec!["issue.owner == $owner", "status INSIDE ['Open', 'Claimed']"];
+    let repos = match repos {
+        Some(repos) => Some(repos.to_vec()),
+        None if !installation.all_repositories => Some(installation.repositories.clone()),
+        None => None,
+    };
+    if repos.is_some() {
+        conditions.push("issue.repo INSIDE $repos");
+    }
+
+    state
+        .db_conn
+        .query(format!(
+            "UPDATE Bounty SET frozen = $frozen WHERE {}",
+            conditions.join(" AND ")
+        ))
+        .bind(("owner", &installation.account))
+        .bind(("repos", repos))
+        .bind(("frozen", frozen))
+        .await
+        .unwrap();
+}
+
+/// Freeze every unfinished bounty on an account the app lost access to
+async fn freeze_account(state: &AppState, account: &str) {
+    state
+        .db_conn
+        .query("UPDATE Bounty SET frozen = true WHERE issue.owner == $owner AND status INSIDE ['Open', 'Claimed']")
+        .bind(("owner", account))
+        .await
+        .unwrap();
+}
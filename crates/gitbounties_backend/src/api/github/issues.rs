@@ -0,0 +1,144 @@
+//! Keep bounties in sync with changes made to their github issue
+
+use log::{debug, info};
+
+use super::parse_github_url;
+use crate::{
+    models::{Bounty, Issue},
+    AppState,
+};
+
+/// Bounties attached to an issue, whatever their status
+async fn bounties_for_issue(state: &AppState, issue: &Issue) -> Vec<Bounty> {
+    let mut res = state
+        .db_conn
+        .query("SELECT * FROM Bounty WHERE issue == $issue")
+        .bind(("issue", issue))
+        .await
+        .unwrap();
+    res.take(0).unwrap()
+}
+
+/// Copy the title, body and labels of an issue onto its bounties
+///
+/// Handles the `edited`, `labeled`, `unlabeled` and `reopened` actions, which all carry the
+/// current state of the issue.
+pub async fn issue_updated_webhook(state: &AppState, payload: &serde_json::Value) {
+    let html_url = payload["html_url"].as_str().expect("Couldn't get html url");
+    let issue = parse_github_url(html_url);
+
+    let title = payload["title"].as_str().expect("Couldn't get issue title");
+    let description = payload["body"].as_str().unwrap_or_default();
+    let labels = payload["labels"]
+        .as_array()
+        .expect("Couldn't get issue labels")
+        .iter()
+        .map(|label| label["name"].as_str().unwrap().to_string())
+        .collect::<Vec<_>>();
+
+    let bounties = bounties_for_issue(state, &issue).await;
+    if bounties.is_empty() {
+        debug!("Could not find associated bounty");
+        return;
+    }
+
+    for bounty in bounties {
+        // the bounty label is managed by us, don't treat it as one of the issue's labels
+        let labels = labels
+            .iter()
+            .filter(|label| Some(*label) != bounty.label.as_ref())
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut res = state
+            .db_conn
+            .query("UPDATE $bounty SET title = $title, description = $description, labels = $labels, issue_deleted = false RETURN AFTER")
+            .bind(("bounty", &bounty.id))
+            .bind(("title", title))
+            .bind(("description", description))
+            .bind(("labels", &labels))
+            .await
+            .unwrap();
+        let updated: Option<Bounty> = res.take(0).unwrap();
+
+        if let Some(updated) = updated {
+            state.search.write().await.insert_bounty(&updated);
+        }
+    }
+
+    debug!(
+        "synced bounties on {}/{}/{}",
+        issue.owner, issue.repo, issue.issue_id
+    );
+}
+
+/// Follow an issue that was moved to another repository
+pub async fn issue_transferred_webhook(state: &AppState, payload: &serde_json::Value) {
+    let old_url = payload["issue"]["html_url"]
+        .as_str()
+        .expect("Couldn't get html url");
+    let old_issue = parse_github_url(old_url);
+
+    let new_url = payload["changes"]["new_issue"]["html_url"]
+        .as_str()
+        .expect("Couldn't get new issue html url");
+    let new_issue = parse_github_url(new_url);
+    let private = payload["changes"]["new_repository"]["private"]
+        .as_bool()
+        .unwrap_or(true);
+
+    let mut res = state
+        .db_conn
+        .query("UPDATE Bounty SET issue = $new_issue, private = $private WHERE issue == $old_issue RETURN AFTER")
+        .bind(("old_issue", &old_issue))
+        .bind(("new_issue", &new_issue))
+        .bind(("private", private))
+        .await
+        .unwrap();
+    let updated: Vec<Bounty> = res.take(0).unwrap();
+
+    if updated.is_empty() {
+        debug!("Could not find associated bounty");
+        return;
+    }
+
+    let mut search = state.search.write().await;
+    for bounty in updated.iter() {
+        search.insert_bounty(bounty);
+    }
+
+    info!(
+        "moved {} bounties from {}/{}/{} to {}/{}/{}",
+        updated.len(),
+        old_issue.owner,
+        old_issue.repo,
+        old_issue.issue_id,
+        new_issue.owner,
+        new_issue.repo,
+        new_issue.issue_id
+    );
+}
+
+/// Flag the bounties of an issue that was deleted, so their owners can cancel them
+pub async fn issue_deleted_webhook(state: &AppState, payload: &serde_json::Value) {
+    let html_url = payload["html_url"].as_str().expect("Couldn't get html url");
+    let issue = parse_github_url(html_url);
+
+    let mut res = state
+        .db_conn
+        .query("UPDATE Bounty SET issue_deleted = true WHERE issue == $issue RETURN AFTER")
+        .bind(("issue", &issue))
+        .await
+        .unwrap();
+    let deleted: Vec<Bounty> = res.take(0).unwrap();
+
+    let mut search = state.search.write().await;
+    for bounty in deleted.iter().filter_map(|bounty| bounty.id.as_ref()) {
+        search.remove(&bounty.id.to_raw());
+    }
+
+    info!(
+        "issue {}/{}/{} was deleted",
+        issue.owner, issue.repo, issue.issue_id
+    );
+}
@@ -5,26 +5,35 @@
 use std::{collections::HashMap, env, sync::Arc};
 
 use axum::{
+    body::Bytes,
     extract::{Json, Path, Query, State},
+    http::HeaderMap,
     response::{Html, IntoResponse},
     routing::{get, post, MethodRouter},
     Router,
 };
 use axum_login::{axum_sessions::async_session::MemoryStore, extractors::AuthContext};
-use gitbounties_contract::{get_contract, http_provider, parse_address, Middleware};
+use gitbounties_contract::parse_address;
+use hmac::{Hmac, Mac};
 use log::{debug, error, info, warn};
 use reqwest::{Method, StatusCode};
 use serde::Deserialize;
 use serde_json::json;
+use sha2::Sha256;
 use surrealdb::{engine::remote::ws::Ws, opt::auth::Root, sql::Thing, Surreal};
 
 use crate::{
+    claims,
     db::DBConnection,
-    models::{Address, Bounty, BountyStatus, Issue, User},
+    models::{Address, Bounty, BountyStatus, Issue, User, UserWallet},
+    payout::pay_bounty,
     session_auth::{AuthUser, MyAuthContext},
-    AppState,
+    wallet, AppState,
 };
 
+mod installations;
+mod issues;
+
 pub fn router() -> Router<AppState> {
     Router::new()
         .route("/register", get(github_register))
@@ -50,28 +59,83 @@ async fn github_register() -> Html<String> {
     ))
 }
 
-async fn github_webhook(State(state): State<AppState>, Json(payload): Json<serde_json::Value>) {
+async fn github_webhook(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, &'static str) {
+    // only act on deliveries that really come from github
+    if !valid_signature(&state.github_webhook_secret, &headers, &body) {
+        warn!("rejected webhook with invalid signature");
+        return (StatusCode::UNAUTHORIZED, "invalid signature");
+    }
+    let Ok(payload) = serde_json::from_slice::<serde_json::Value>(&body) else {
+        return (StatusCode::BAD_REQUEST, "malformed payload");
+    };
+
     // TODO return proper error to sender
+    let event = headers
+        .get("X-GitHub-Event")
+        .and_then(|event| event.to_str().ok())
+        .unwrap_or_default();
     let action: &str = payload["action"].as_str().expect("Malformed webhook");
-    info!("github hook called: {}", action);
-    match action {
-        "opened" => {
-            // NOTE we probably don't actually care when a PR was opened
-            // TODO PRs also count as issues, make sure, you can check existence of issue field to
-            // make sure
+    info!("github hook called: {} {}", event, action);
+    match (event, action) {
+        ("issues", "opened") => {
             let issue_raw: &serde_json::Value = payload.get("issue").expect("No issue field");
 
             debug!("[webhook] issue opened {issue_raw}");
         },
-        "closed" => {
+        ("issues", "closed") => {
             let issue_raw: &serde_json::Value = payload.get("issue").expect("No issue field");
 
             issue_closed_webhook(&state, issue_raw).await;
         },
+        ("issues", "edited" | "labeled" | "unlabeled" | "reopened") => {
+            let issue_raw: &serde_json::Value = payload.get("issue").expect("No issue field");
+
+            issues::issue_updated_webhook(&state, issue_raw).await;
+        },
+        ("issues", "transferred") => {
+            issues::issue_transferred_webhook(&state, &payload).await;
+        },
+        ("issues", "deleted") => {
+            let issue_raw: &serde_json::Value = payload.get("issue").expect("No issue field");
+
+            issues::issue_deleted_webhook(&state, issue_raw).await;
+        },
+        ("installation", _) => {
+            installations::installation_webhook(&state, action, &payload).await;
+        },
+        ("installation_repositories", _) => {
+            installations::installation_repositories_webhook(&state, action, &payload).await;
+        },
         _ => {
-            warn!("Unhandled action type {}", action);
+            warn!("Unhandled action type {} {}", event, action);
         },
     }
+
+    (StatusCode::OK, "ok")
+}
+
+/// Check the `X-Hub-Signature-256` header github signs webhook deliveries with
+fn valid_signature(secret: &str, headers: &HeaderMap, body: &[u8]) -> bool {
+    let Some(signature) = headers
+        .get("X-Hub-Signature-256")
+        .and_then(|signature| signature.to_str().ok())
+        .and_then(|signature| signature.strip_prefix("sha256="))
+    else {
+        return false;
+    };
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    // compares in constant time
+    mac.verify_slice(&signature).is_ok()
 }
 
 pub async fn issue_closed_webhook(state: &AppState, payload: &serde_json::Value) {
@@ -89,7 +153,7 @@ pub async fn issue_closed_webhook(state: &AppState, payload: &serde_json::Value)
     // Check if issue has a bounty open (and that it's not closed)
     let mut res = state
         .db_conn
-        .query("SELECT * FROM Bounty WHERE issue == $issue AND status = 'Open")
+        .query("SELECT * FROM Bounty WHERE issue == $issue AND issue_deleted != true AND status = 'Open")
         .bind(("issue", &issue))
         .await
         .unwrap();
@@ -111,7 +175,7 @@ pub async fn issue_closed_webhook(state: &AppState, payload: &serde_json::Value)
     // Find the PR that closed this issue
     // TODO find a nicer way to write graphql queries in rust
     let query = format!(
-        r#" {{ repository(name: \"{}\", owner: \"{}\") {{ issue(number: {}) {{ timelineItems(itemTypes: CLOSED_EVENT, last: 1) {{ nodes {{ ... on ClosedEvent {{ createdAt closer {{ __typename ... on PullRequest {{ author {{ login }} }} }} }} }} }} }} }} }} "#,
+        r#" {{ repository(name: \"{}\", owner: \"{}\") {{ issue(number: {}) {{ timelineItems(itemTypes: CLOSED_EVENT, last: 1) {{ nodes {{ ... on ClosedEvent {{ createdAt closer {{ __typename ... on PullRequest {{ number author {{ login }} }} }} }} }} }} }} }} }} "#,
         issue.owner, issue.repo, issue.issue_id
     );
 
@@ -140,73 +204,73 @@ pub async fn issue_closed_webhook(state: &AppState, payload: &serde_json::Value)
     let closer_type = closer["__typename"]
         .as_str()
         .expect("Couldn't get closer type");
-    let closer_user = closer["author"]["login"]
-        .as_str()
-        .expect("Couldn't get closer user");
     if closer_type != "PullRequest" {
         debug!("Issue was not closed by pull request");
         return;
     }
+    let closer_user = closer["author"]["login"]
+        .as_str()
+        .expect("Couldn't get closer user");
+    let pull_request = closer["number"]
+        .as_u64()
+        .expect("Couldn't get pull request number");
 
     debug!("Got closer user {closer_user}");
 
     // Get the closer users's public key
-    let closer_user_data: User = state
-        .db_conn
-        .select(("Users", closer_user))
-        .await
-        .expect("User should exist in database");
+    let closer_user_data: Option<User> =
+        state.db_conn.select(("Users", closer_user)).await.unwrap();
 
-    // Get the token id for the bounty
-    let token_id = bounty.token_id;
+    let Some(closer_user_data) = closer_user_data else {
+        // hold the reward until the author of the pull request registers
+        claims::create_claim(state, &bounty, closer_user, pull_request).await;
 
-    // fetch closer wallet address
-    resolve_bounty(token_id, &closer_user_data.wallet_address).await;
+        let register_url = &state.register_url;
+        post_issue_comment(
+            state,
+            &installation_access_token,
+            &issue.owner,
+            &issue.repo,
+            pull_request,
+            &format!(
+                "@{closer_user} this pull request closed a bounty on GitBounties! Register at {register_url} and verify a wallet to receive the reward."
+            ),
+        )
+        .await;
+        return;
+    };
 
-    // mark the issue as resolved
+    // fetch closer wallet address
+    let closer_wallet_address = closer_user_data.payout_wallet();
+    pay_bounty(state, &bounty, closer_user, &closer_wallet_address).await;
 
-    println!("[webhook] issue closed {body}");
+    debug!("[webhook] issue closed {body}");
 }
 
-pub(crate) async fn resolve_bounty(token_id: u64, closer_wallet_address: &Address) {
-    let provider = http_provider();
-    let contract_addr = env::var("CONTRACT_ADDRESS").expect("Couldnt get CONTRACT_ADDRESS env var");
-    let wallet_private_key =
-        env::var("WALLET_PRIVATE_KEY").expect("Couldnt get WALLET_PRIVATE_KEY env var");
-    let contract = get_contract(&provider, &contract_addr, &wallet_private_key)
-        .await
-        .expect("Coudln't initalize contract");
-
-    let target_balance = provider
-        .get_balance(*closer_wallet_address, None)
-        .await
-        .unwrap();
-    debug!(
-        "target wallet balance before transaction {}",
-        target_balance
-    );
-
-    let _reciept = contract
-        .transfer_token(token_id.into(), *closer_wallet_address)
-        .send()
-        .await
-        .unwrap()
-        .await
-        .unwrap();
-
-    let _reciept = contract
-        .burn(token_id.into())
+/// Leave a comment on an issue or pull request
+pub async fn post_issue_comment(
+    state: &AppState,
+    installation_access_token: &str,
+    owner: &str,
+    repo: &str,
+    number: u64,
+    body: &str,
+) {
+    let res = state
+        .reqwest_github(
+            Method::POST,
+            &format!("https://api.github.com/repos/{owner}/{repo}/issues/{number}/comments"),
+            installation_access_token,
+        )
+        .json(&json!({ "body": body }))
         .send()
         .await
-        .unwrap()
-        .await
         .unwrap();
 
-    let target_balance = provider
-        .get_balance(*closer_wallet_address, None)
-        .await
-        .unwrap();
-    debug!("target wallet balance after transaction {}", target_balance);
+    if !res.status().is_success() {
+        let body = res.text().await.unwrap();
+        warn!("failed to comment on {owner}/{repo}#{number}: {body}");
+    }
 }
 
 /// Parses github url to fetch issue info
@@ -228,6 +292,19 @@ fn parse_github_url(url: &str) -> Issue {
 pub struct RegisterQuery {
     code: String,
     wallet_address: String,
+    /// Nonce issued by `/auth/nonce` for `wallet_address`
+    nonce: String,
+    /// Signature of the ownership message, proving the user controls `wallet_address`
+    signature: String,
+}
+
+/// Query of the install callback, the wallet and its proof are only needed to register new users
+#[derive(Debug, Deserialize)]
+pub struct InstallQuery {
+    code: String,
+    wallet_address: Option<String>,
+    nonce: Option<String>,
+    signature: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -237,7 +314,7 @@ pub struct CodeQuery {
 
 /// Callback when installing the github app
 async fn github_callback_install(
-    Query(params): Query<RegisterQuery>,
+    Query(params): Query<InstallQuery>,
     mut auth: MyAuthContext,
     State(state): State<AppState>,
 ) -> impl IntoResponse {
@@ -256,14 +333,26 @@ async fn github_callback_install(
 
     if res.is_none() {
         // register user if not exist
+        let (Some(wallet_address), Some(nonce), Some(signature)) =
+            (&params.wallet_address, &params.nonce, &params.signature)
+        else {
+            return (
+                StatusCode::BAD_REQUEST,
+                "wallet_address, nonce and signature are required to register",
+            );
+        };
 
-        let Ok(wallet_address) = parse_address(&params.wallet_address) else {
+        let Ok(wallet_address) = parse_address(wallet_address) else {
             warn!(
                 "failed to decode wallet_address {}",
-                &params.wallet_address.trim_start_matches("0x")
+                wallet_address.trim_start_matches("0x")
             );
             return (StatusCode::BAD_REQUEST, "invalid wallet address");
         };
+        if let Err(err) = wallet::verify_ownership(&state, &wallet_address, nonce, signature).await
+        {
+            return err;
+        }
         register_user(&state, &username, &access_token, &wallet_address).await;
 
         // register_user(&state, &username, &access_token, &wallet_address).await;
@@ -311,6 +400,11 @@ async fn github_callback_register(
         );
         return (StatusCode::BAD_REQUEST, "invalid wallet address");
     };
+    if let Err(err) =
+        wallet::verify_ownership(&state, &wallet_address, &params.nonce, &params.signature).await
+    {
+        return err;
+    }
     register_user(&state, &username, &access_token, &wallet_address).await;
     auth.login(&AuthUser {
         id: String::from(&username),
@@ -366,6 +460,13 @@ async fn register_user(
             username: username.to_string(),
             github_installations: vec![],
             wallet_address: *wallet_address,
+            wallets: vec![UserWallet {
+                address: *wallet_address,
+                label: None,
+                chain_id: None,
+                verified: chrono::offset::Utc::now(),
+                removed: None,
+            }],
         })
         .await
         .unwrap();
@@ -373,6 +474,9 @@ async fn register_user(
     debug!("registered user res {res:?}");
 
     update_user_installations(state, username, access_token).await;
+
+    // pay out any bounty the user closed before registering
+    claims::settle_claims(state, username, wallet_address).await;
 }
 
 async fn update_user_installations(state: &AppState, username: &str, access_token: &str) {
@@ -529,12 +633,30 @@ pub async fn get_installation_access_token(state: &AppState, installation_id: u6
 
 #[cfg(test)]
 mod tests {
-    use gitbounties_contract::H160;
+    use axum::http::{HeaderMap, HeaderValue};
+    use log::debug;
+
+    use super::valid_signature;
+    use crate::{api::github::issue_closed_webhook, AppState};
+
+    #[test]
+    fn webhook_signature_must_match_secret_and_body() {
+        // example from github's webhook validation docs
+        let secret = "It's a Secret to Everybody";
+        let body = b"Hello, World!";
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "X-Hub-Signature-256",
+            HeaderValue::from_static(
+                "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17",
+            ),
+        );
 
-    use crate::{
-        api::github::{issue_closed_webhook, resolve_bounty},
-        AppState,
-    };
+        assert!(valid_signature(secret, &headers, body));
+        assert!(!valid_signature("another secret", &headers, body));
+        assert!(!valid_signature(secret, &headers, b"Hello, World?"));
+        assert!(!valid_signature(secret, &HeaderMap::new(), body));
+    }
 
     // #[tokio::test]
     // async fn test_issue_closed_webhook() {
@@ -557,17 +679,6 @@ mod tests {
             .await
             .unwrap();
 
-        println!("res {res:?}");
-    }
-
-    #[tokio::test]
-    async fn test_resolved_bounty() {
-        dotenvy::dotenv().unwrap();
-
-        let token_id = 1;
-        let closer_wallet_address: H160 = "0x3C44CdDdB6a900fa2b585dd299e03d12FA4293BC"
-            .parse()
-            .unwrap();
-        resolve_bounty(token_id, &closer_wallet_address).await;
+        debug!("res {res:?}");
     }
 }
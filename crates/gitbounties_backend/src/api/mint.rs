@@ -0,0 +1,470 @@
+//! Backend driven minting, so a bounty is only ever stored once the token backing it exists
+//!
+//! Users mint from their own wallet: `/mint/prepare` returns the transactions to sign and
+//! `/mint/confirm` creates the bounty once the mint is mined. Rewards in ERC-20 tokens are funded
+//! by transferring the tokens to the token bound account instead of calling `addETH`, and only
+//! tokens listed in the chain's `REWARD_TOKENS` are accepted.
+//!
+//! Organizations listed in `CUSTODIAL_ORGS` can instead have the operator wallet mint and fund the
+//! token with `/mint/custodial`. What the operator funds is capped per organization and asset by
+//! `CUSTODIAL_BUDGET`, and custodial bounties are refunded to the operator rather than the user
+//! that created them.
+
+use std::{collections::HashMap, env, fmt::Display, time::Duration};
+
+use axum::{
+    extract::{Json, Query, State},
+    routing::post,
+    Extension, Router,
+};
+use gitbounties_contract::{
+    minted_token, wait_for_receipt, Address, Bytes, ChainConfig, Operator, TransactionReceipt,
+    H256, IERC20, U256,
+};
+use log::{info, warn};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use super::bounty::{
+    authorize_issue, check_reward_token, insert_bounty, select_chain, user_wallets, verify_token,
+    IssueQuery, VerifiedToken,
+};
+use crate::{
+    models::{Amount, Bounty},
+    session_auth::{AuthUser, MyRequireAuthorizationLayer},
+    AppState,
+};
+
+/// How long to wait for a mint transaction to be confirmed
+const MINT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/prepare",
+            post(prepare).layer(MyRequireAuthorizationLayer::login()),
+        )
+        .route(
+            "/confirm",
+            post(confirm).layer(MyRequireAuthorizationLayer::login()),
+        )
+        .route(
+            "/custodial",
+            post(custodial).layer(MyRequireAuthorizationLayer::login()),
+        )
+}
+
+/// Organizations the operator funds bounties for, and how much it may fund
+#[derive(Debug)]
+pub struct Custodial {
+    orgs: Vec<String>,
+    /// Most each organization can have escrowed in live custodial bounties, per reward token with
+    /// `None` for native ETH. Assets without a budget can't be used for custodial bounties.
+    budgets: HashMap<Option<Address>, Amount>,
+    /// Held while minting, so concurrent requests can't overrun a budget
+    lock: tokio::sync::Mutex<()>,
+}
+
+impl Custodial {
+    /// Read `CUSTODIAL_ORGS` and `CUSTODIAL_BUDGET`, the budget being a comma separated list of
+    /// `<asset>=<amount>` where the asset is `ETH` or an ERC-20 token address and the amount is in
+    /// the smallest unit of the asset
+    pub fn from_env() -> Custodial {
+        Custodial::parse(
+            &env::var("CUSTODIAL_ORGS").unwrap_or_default(),
+            &env::var("CUSTODIAL_BUDGET").unwrap_or_default(),
+        )
+    }
+
+    fn parse(orgs: &str, budget: &str) -> Custodial {
+        let orgs = orgs
+            .split(',')
+            .map(|org| org.trim().to_string())
+            .filter(|org| !org.is_empty())
+            .collect();
+
+        let budgets = budget
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                let (asset, amount) = entry
+                    .split_once('=')
+                    .expect("CUSTODIAL_BUDGET entries should be <asset>=<amount>");
+                let token = match asset.trim() {
+                    "ETH" => None,
+                    token => Some(
+                        token
+                            .parse::<Address>()
+                            .expect("CUSTODIAL_BUDGET asset should be ETH or a token address"),
+                    ),
+                };
+                let amount = amount
+                    .parse::<Amount>()
+                    .expect("CUSTODIAL_BUDGET amount should be a decimal integer");
+                (token, amount)
+            })
+            .collect();
+
+        Custodial {
+            orgs,
+            budgets,
+            lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    fn is_custodial(&self, org: &str) -> bool {
+        self.orgs.iter().any(|custodial_org| custodial_org == org)
+    }
+}
+
+/// Unsigned transaction for the user's wallet to sign and send
+#[derive(Debug, Serialize)]
+pub struct PreparedTransaction {
+    pub to: Address,
+    pub data: Bytes,
+    pub value: U256,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PrepareBody {
+    pub reward: Amount,
+    /// ERC-20 token the reward is paid in, leave empty for native ETH
+    pub reward_token: Option<Address>,
+    /// Token to fund, leave empty to get the mint transaction
+    pub token_id: Option<u64>,
+    /// Chain to mint on, defaults to the default chain
+    pub chain_id: Option<u64>,
+}
+
+/// Build the transaction minting a bounty token, or funding it once its id is known
+pub async fn prepare(
+    State(state): State<AppState>,
+    Query(query): Query<IssueQuery>,
+    Extension(auth_user): Extension<AuthUser>,
+    Json(payload): Json<PrepareBody>,
+) -> Result<Json<PreparedTransaction>, (StatusCode, String)> {
+    authorize_issue(&state, &auth_user.id, &query).await?;
+
+    let chain = select_chain(&state, payload.chain_id)?;
+    check_reward_token(chain, payload.reward_token)?;
+    let contract = chain
+        .read_only_contract()
+        .expect("Coudln't initalize contract");
+
+    let reward = payload.reward.0;
+    let prepared = match (payload.token_id, payload.reward_token) {
+        (None, _) => PreparedTransaction {
+            to: contract.address(),
+            data: contract
+                .mint()
+                .calldata()
+                .expect("Contract call should have calldata"),
+            value: U256::zero(),
+        },
+        (Some(token_id), None) => PreparedTransaction {
+            to: contract.address(),
+            data: contract
+                .add_eth(token_id.into())
+                .calldata()
+                .expect("Contract call should have calldata"),
+            value: reward,
+        },
+        (Some(token_id), Some(reward_token)) => {
+            let account = contract
+                .get_account(token_id.into())
+                .call()
+                .await
+                .map_err(chain_error)?;
+            PreparedTransaction {
+                to: reward_token,
+                data: IERC20::new(reward_token, contract.client())
+                    .transfer(account, reward)
+                    .calldata()
+                    .expect("Contract call should have calldata"),
+                value: U256::zero(),
+            }
+        },
+    };
+
+    Ok(Json(prepared))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConfirmBody {
+    pub reward: Amount,
+    /// ERC-20 token the reward is paid in, leave empty for native ETH
+    pub reward_token: Option<Address>,
+    /// Hash of the mint transaction sent by the user
+    pub tx_hash: H256,
+    /// Chain the transaction was sent on, defaults to the default chain
+    pub chain_id: Option<u64>,
+}
+
+/// Create the bounty for a token the user minted and funded
+pub async fn confirm(
+    State(state): State<AppState>,
+    Query(query): Query<IssueQuery>,
+    Extension(auth_user): Extension<AuthUser>,
+    Json(payload): Json<ConfirmBody>,
+) -> Result<Json<Bounty>, (StatusCode, String)> {
+    let (installation_access_token, user_data) =
+        authorize_issue(&state, &auth_user.id, &query).await?;
+
+    let chain = select_chain(&state, payload.chain_id)?;
+    let provider = chain.provider().expect("Couldn't connect to rpc endpoint");
+    let receipt = tokio::time::timeout(
+        MINT_TIMEOUT,
+        wait_for_receipt(&provider, payload.tx_hash, chain.confirmations as usize),
+    )
+    .await
+    .map_err(|_| {
+        (
+            StatusCode::GATEWAY_TIMEOUT,
+            "Mint transaction was not confirmed in time".to_string(),
+        )
+    })?
+    .map_err(chain_error)?
+    .ok_or((StatusCode::NOT_FOUND, "Transaction not found".to_string()))?;
+
+    let token_id = minted_token_id(chain, &receipt)?;
+
+    let token = verify_token(
+        &state,
+        chain,
+        &user_wallets(&user_data),
+        token_id,
+        payload.reward,
+        payload.reward_token,
+    )
+    .await?;
+
+    let bounty = insert_bounty(
+        &state,
+        &auth_user.id,
+        &query,
+        &installation_access_token,
+        chain,
+        token,
+    )
+    .await?;
+
+    Ok(Json(bounty))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CustodialBody {
+    pub reward: Amount,
+    /// ERC-20 token to pay the reward in from the operator's balance, leave empty for native ETH
+    pub reward_token: Option<Address>,
+    /// Chain to mint on, defaults to the default chain
+    pub chain_id: Option<u64>,
+}
+
+/// Mint and fund a bounty token with the operator wallet on behalf of a custodial organization
+pub async fn custodial(
+    State(state): State<AppState>,
+    Query(query): Query<IssueQuery>,
+    Extension(auth_user): Extension<AuthUser>,
+    Json(payload): Json<CustodialBody>,
+) -> Result<Json<Bounty>, (StatusCode, String)> {
+    if !state.custodial.is_custodial(&query.owner) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Organization is not custodial".into(),
+        ));
+    }
+
+    let (installation_access_token, _user_data) =
+        authorize_issue(&state, &auth_user.id, &query).await?;
+
+    let chain = select_chain(&state, payload.chain_id)?;
+    check_reward_token(chain, payload.reward_token)?;
+
+    let _guard = state.custodial.lock.lock().await;
+    let Some(budget) = state.custodial.budgets.get(&payload.reward_token) else {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Asset can't be used for custodial bounties".into(),
+        ));
+    };
+    let escrowed = custodial_escrow(&state, &query.owner, payload.reward_token).await;
+    if escrowed.saturating_add(payload.reward) > *budget {
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!("Reward exceeds the custodial budget, {escrowed} of {budget} is escrowed"),
+        ));
+    }
+
+    let operator = state.chains.operator(chain.chain_id).ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Chain has no operator".to_string(),
+    ))?;
+    let contract = operator.contract(chain.contract_address);
+
+    let tx_hash = operator
+        .send(contract.mint().tx)
+        .await
+        .map_err(chain_error)?;
+    let receipt = operator_receipt(chain, operator, tx_hash).await?;
+    let token_id = minted_token_id(chain, &receipt)?;
+
+    let reward = payload.reward.0;
+    let tx = match payload.reward_token {
+        None => contract.add_eth(token_id.into()).value(reward).tx,
+        Some(reward_token) => {
+            let account = contract
+                .get_account(token_id.into())
+                .call()
+                .await
+                .map_err(chain_error)?;
+            IERC20::new(reward_token, contract.client())
+                .transfer(account, reward)
+                .tx
+        },
+    };
+    let tx_hash = operator.send(tx).await.map_err(chain_error)?;
+    operator_receipt(chain, operator, tx_hash).await?;
+
+    info!(
+        "minted token {} for {}/{}/{} on behalf of {}",
+        token_id, query.owner, query.repo, query.issue, auth_user.id
+    );
+
+    let token = VerifiedToken {
+        custodial: true,
+        ..verify_token(
+            &state,
+            chain,
+            &[operator.address()],
+            token_id,
+            payload.reward,
+            payload.reward_token,
+        )
+        .await?
+    };
+
+    let bounty = insert_bounty(
+        &state,
+        &auth_user.id,
+        &query,
+        &installation_access_token,
+        chain,
+        token,
+    )
+    .await?;
+
+    Ok(Json(bounty))
+}
+
+/// Rewards in an asset held by live custodial bounties of an organization
+async fn custodial_escrow(state: &AppState, org: &str, reward_token: Option<Address>) -> Amount {
+    let mut res = state
+        .db_conn
+        .query("SELECT * FROM Bounty WHERE custodial == true AND issue.owner == $org AND status INSIDE ['Open', 'Claimed']")
+        .bind(("org", org))
+        .await
+        .unwrap();
+    let bounties: Vec<Bounty> = res.take(0).unwrap();
+
+    bounties
+        .iter()
+        .filter(|bounty| bounty.asset.token == reward_token)
+        .fold(Amount::default(), |total, bounty| {
+            total.saturating_add(bounty.reward)
+        })
+}
+
+/// Wait for a transaction sent by the operator to be confirmed
+async fn operator_receipt(
+    chain: &ChainConfig,
+    operator: &Operator,
+    tx_hash: H256,
+) -> Result<TransactionReceipt, (StatusCode, String)> {
+    tokio::time::timeout(
+        MINT_TIMEOUT,
+        wait_for_receipt(operator.provider(), tx_hash, chain.confirmations as usize),
+    )
+    .await
+    .map_err(|_| {
+        (
+            StatusCode::GATEWAY_TIMEOUT,
+            "Transaction was not confirmed in time".to_string(),
+        )
+    })?
+    .map_err(chain_error)?
+    .ok_or((
+        StatusCode::BAD_GATEWAY,
+        "Transaction was dropped".to_string(),
+    ))
+}
+
+/// Id of the token minted by a successful transaction to the bounty contract
+fn minted_token_id(
+    chain: &ChainConfig,
+    receipt: &TransactionReceipt,
+) -> Result<u64, (StatusCode, String)> {
+    if receipt.status != Some(1.into()) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Mint transaction failed".to_string(),
+        ));
+    }
+
+    let transfer = minted_token(receipt, chain.contract_address).ok_or((
+        StatusCode::BAD_REQUEST,
+        "Transaction did not mint a bounty token".to_string(),
+    ))?;
+
+    Ok(transfer.token_id.as_u64())
+}
+
+pub(crate) fn chain_error(err: impl Display) -> (StatusCode, String) {
+    warn!("chain request failed: {err}");
+    (
+        StatusCode::BAD_GATEWAY,
+        "Failed to reach the chain".to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Custodial;
+    use crate::models::{Address, Amount};
+
+    #[test]
+    fn custodial_orgs_and_budgets_are_parsed() {
+        let token = "0x5FbDB2315678afecb367f032d93F642f64180aa3";
+        let custodial = Custodial::parse(
+            " gitbounties, acme ,,",
+            &format!("ETH=1000000000000000000, {token}=500"),
+        );
+
+        assert!(custodial.is_custodial("gitbounties"));
+        assert!(custodial.is_custodial("acme"));
+        assert!(!custodial.is_custodial("Acme"));
+        assert!(!custodial.is_custodial(""));
+
+        assert_eq!(custodial.budgets.len(), 2);
+        assert_eq!(
+            custodial.budgets[&None],
+            Amount::from(1_000_000_000_000_000_000)
+        );
+        assert_eq!(
+            custodial.budgets[&Some(token.parse::<Address>().unwrap())],
+            Amount::from(500)
+        );
+    }
+
+    #[test]
+    fn nothing_is_custodial_by_default() {
+        let custodial = Custodial::parse("", "");
+        assert!(custodial.orgs.is_empty());
+        assert!(custodial.budgets.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "CUSTODIAL_BUDGET entries")]
+    fn budget_entries_need_an_asset() {
+        Custodial::parse("acme", "1000");
+    }
+}
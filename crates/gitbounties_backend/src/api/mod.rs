@@ -2,6 +2,11 @@ pub mod auth;
 pub mod bounty;
 pub mod github;
 pub mod issue;
+pub mod mint;
+pub mod nft;
+pub mod public;
+pub mod stats;
+pub mod user;
 
 use std::env;
 
@@ -24,6 +29,11 @@ pub fn router() -> Router<AppState> {
         .nest("/bounty", bounty::router())
         .nest("/auth", auth::router())
         .nest("/issue", issue::router())
+        .nest("/mint", mint::router())
+        .nest("/nft", nft::router())
+        .nest("/public", public::router())
+        .nest("/stats", stats::router())
+        .nest("/user", user::router())
 }
 
 async fn health() -> &'static str {
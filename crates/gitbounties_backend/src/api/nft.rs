@@ -0,0 +1,256 @@
+//! ERC-721 metadata for bounty tokens, served as the `tokenURI` of the contract
+//!
+//! Wallets and marketplaces fetch `/nft/{chain_id}/{token_id}` for the metadata, whose image
+//! points to a card rendered as SVG by `/nft/{chain_id}/{token_id}/image.svg`. The base URI of the
+//! contract on each chain should be `{PUBLIC_API_URL}/nft/{chain_id}/`. Bounties on private
+//! repositories only expose their reward and status.
+
+use axum::{
+    extract::{Path, State},
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use reqwest::StatusCode;
+use serde::Serialize;
+
+use super::{
+    bounty::select_chain,
+    public::{cached, cached_json},
+};
+use crate::{models::Bounty, AppState};
+
+/// Longest line of the bounty title on the card, in characters
+const CARD_LINE_LENGTH: usize = 26;
+/// Most lines of the bounty title shown on the card
+const CARD_TITLE_LINES: usize = 3;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/:chain_id/:token_id", get(metadata))
+        .route("/:chain_id/:token_id/image.svg", get(image))
+}
+
+/// Metadata following the ERC-721 metadata JSON schema, with OpenSea style attributes
+#[derive(Debug, Serialize)]
+pub struct TokenMetadata {
+    pub name: String,
+    pub description: String,
+    pub image: String,
+    pub external_url: Option<String>,
+    pub attributes: Vec<Attribute>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Attribute {
+    pub trait_type: &'static str,
+    pub value: serde_json::Value,
+}
+
+impl Attribute {
+    fn new(trait_type: &'static str, value: impl Into<serde_json::Value>) -> Self {
+        Attribute {
+            trait_type,
+            value: value.into(),
+        }
+    }
+}
+
+/// Metadata of a bounty token
+pub async fn metadata(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((chain_id, token_id)): Path<(u64, u64)>,
+) -> Response {
+    let bounty = match token_bounty(&state, chain_id, token_id).await {
+        Ok(bounty) => bounty,
+        Err(err) => return err.into_response(),
+    };
+
+    let image = format!(
+        "{}/nft/{chain_id}/{token_id}/image.svg",
+        state.public_api_url
+    );
+
+    let reward = bounty.asset.format(bounty.reward);
+    let status = format!("{:?}", bounty.status);
+
+    let metadata = if bounty.private {
+        TokenMetadata {
+            name: format!("GitBounties bounty #{token_id}"),
+            description: format!("Bounty of {reward} on a private repository"),
+            image,
+            external_url: None,
+            attributes: vec![
+                Attribute::new("Reward", reward),
+                Attribute::new("Status", status),
+            ],
+        }
+    } else {
+        let issue = &bounty.issue;
+        TokenMetadata {
+            name: format!(
+                "{}/{}#{}: {}",
+                issue.owner, issue.repo, issue.issue_id, bounty.title
+            ),
+            description: bounty.description.clone(),
+            image,
+            external_url: Some(format!(
+                "https://github.com/{}/{}/issues/{}",
+                issue.owner, issue.repo, issue.issue_id
+            )),
+            attributes: vec![
+                Attribute::new("Repository", format!("{}/{}", issue.owner, issue.repo)),
+                Attribute::new("Issue", issue.issue_id),
+                Attribute::new("Reward", reward),
+                Attribute::new("Status", status),
+            ],
+        }
+    };
+
+    cached_json(&headers, &metadata)
+}
+
+/// Card showing the bounty, used as the image of the token
+pub async fn image(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((chain_id, token_id)): Path<(u64, u64)>,
+) -> Response {
+    let bounty = match token_bounty(&state, chain_id, token_id).await {
+        Ok(bounty) => bounty,
+        Err(err) => return err.into_response(),
+    };
+
+    cached(
+        &headers,
+        "image/svg+xml",
+        render_card(&bounty, token_id).into_bytes(),
+    )
+}
+
+/// Bounty backed by a token of the current contract on a chain
+async fn token_bounty(
+    state: &AppState,
+    chain_id: u64,
+    token_id: u64,
+) -> Result<Bounty, (StatusCode, String)> {
+    let chain = select_chain(state, Some(chain_id))?;
+
+    let mut res = state
+        .db_conn
+        .query("SELECT * FROM Bounty WHERE token_id == $token_id AND chain_id == $chain_id AND contract_address == $contract_address")
+        .bind(("token_id", token_id))
+        .bind(("chain_id", chain.chain_id))
+        .bind(("contract_address", chain.contract_address))
+        .await
+        .unwrap();
+    let bounty: Option<Bounty> = res.take(0).unwrap();
+
+    bounty.ok_or((StatusCode::NOT_FOUND, "Token not found".into()))
+}
+
+fn render_card(bounty: &Bounty, token_id: u64) -> String {
+    let (heading, title) = if bounty.private {
+        (
+            "Private repository".to_string(),
+            format!("Bounty #{token_id}"),
+        )
+    } else {
+        (
+            format!(
+                "{}/{}#{}",
+                bounty.issue.owner, bounty.issue.repo, bounty.issue.issue_id
+            ),
+            bounty.title.clone(),
+        )
+    };
+
+    let title_lines: String = wrap(&title, CARD_LINE_LENGTH, CARD_TITLE_LINES)
+        .iter()
+        .enumerate()
+        .map(|(i, line)| {
+            format!(
+                r#"<text x="32" y="{}" font-size="24" font-weight="bold">{}</text>"#,
+                140 + i * 32,
+                escape_xml(line)
+            )
+        })
+        .collect();
+
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="400" height="400" viewBox="0 0 400 400" font-family="sans-serif" fill="#ffffff"><rect width="400" height="400" rx="24" fill="#161b22"/><text x="32" y="56" font-size="20" fill="#f9d71c">💰 GitBounties</text><text x="32" y="96" font-size="16" fill="#8b949e">{heading}</text>{title_lines}<text x="32" y="312" font-size="32" font-weight="bold" fill="#f9d71c">{reward}</text><text x="32" y="356" font-size="16" fill="#8b949e">{status}</text></svg>"##,
+        heading = escape_xml(&heading),
+        reward = escape_xml(&bounty.asset.format(bounty.reward)),
+        status = format_args!("{:?}", bounty.status),
+    )
+}
+
+/// Split text on words into at most `max_lines` lines, ending with an ellipsis if it didn't fit
+fn wrap(text: &str, line_length: usize, max_lines: usize) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    let mut current = String::new();
+    for word in text.split_whitespace() {
+        if !current.is_empty() && current.chars().count() + 1 + word.chars().count() > line_length {
+            lines.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.push_str(word);
+    }
+    if !current.is_empty() {
+        lines.push(current);
+    }
+
+    if lines.len() > max_lines {
+        lines.truncate(max_lines);
+        if let Some(last) = lines.last_mut() {
+            last.push('…');
+        }
+    }
+    // a single word longer than a line is cut
+    for line in lines.iter_mut() {
+        if line.chars().count() > line_length + 1 {
+            *line = line.chars().take(line_length).chain(['…']).collect();
+        }
+    }
+
+    lines
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_xml, wrap};
+
+    #[test]
+    fn wrap_title_on_words() {
+        assert_eq!(
+            wrap("Fix crash when the config file is missing", 16, 3),
+            vec!["Fix crash when", "the config file", "is missing"]
+        );
+        assert_eq!(
+            wrap("Fix crash when the config file is missing", 16, 2),
+            vec!["Fix crash when", "the config file…"]
+        );
+        assert_eq!(wrap("Supercalifragilistic", 10, 3), vec!["Supercalif…"]);
+        assert!(wrap("", 10, 3).is_empty());
+    }
+
+    #[test]
+    fn escape_markup() {
+        assert_eq!(
+            escape_xml(r#"<script>"a" & 'b'</script>"#),
+            "&lt;script&gt;&quot;a&quot; &amp; &apos;b&apos;&lt;/script&gt;"
+        );
+    }
+}
@@ -0,0 +1,241 @@
+//! Read-only routes that don't require logging in
+//!
+//! Only open bounties on public repositories are exposed, and fields that are only relevant to the
+//! owner of the bounty are left out. Responses carry caching headers so they can be served from a
+//! CDN.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use reqwest::StatusCode;
+use serde::Serialize;
+
+use super::bounty::{onchain_state, query_bounties, ListQuery};
+use crate::{
+    models::{Amount, Asset, Bounty, BountyStatus, Issue},
+    AppState,
+};
+
+/// How long browsers may cache a response, in seconds
+const MAX_AGE: u32 = 60;
+/// How long shared caches (CDNs) may cache a response, in seconds
+const SHARED_MAX_AGE: u32 = 300;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/bounty", get(list))
+        .route("/bounty/:id", get(get_bounty))
+        .route("/bounty/:id/onchain", get(onchain))
+}
+
+/// Publicly visible view of a bounty
+#[derive(Debug, Serialize)]
+pub struct PublicBounty {
+    pub id: Option<String>,
+    pub reward: Amount,
+    pub asset: Asset,
+    pub issue: Issue,
+    pub status: BountyStatus,
+    pub title: String,
+    pub description: String,
+    pub labels: Vec<String>,
+    pub created: chrono::DateTime<chrono::offset::Utc>,
+    pub token_id: u64,
+}
+
+impl From<Bounty> for PublicBounty {
+    fn from(bounty: Bounty) -> Self {
+        PublicBounty {
+            id: bounty.id.map(|id| id.id.to_raw()),
+            reward: bounty.reward,
+            asset: bounty.asset,
+            issue: bounty.issue,
+            status: bounty.status,
+            title: bounty.title,
+            description: bounty.description,
+            labels: bounty.labels,
+            created: bounty.created,
+            token_id: bounty.token_id,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PublicBountyPage {
+    pub bounties: Vec<PublicBounty>,
+    pub next_page: Option<String>,
+}
+
+/// List open bounties on public repositories
+pub async fn list(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<ListQuery>,
+) -> Response {
+    let page = match query_bounties(&state, params, None, true).await {
+        Ok(page) => page,
+        Err(err) => return err.into_response(),
+    };
+
+    let page = PublicBountyPage {
+        bounties: page.bounties.into_iter().map(PublicBounty::from).collect(),
+        next_page: page.next_page,
+    };
+
+    cached_json(&headers, &page)
+}
+
+/// Get a single open bounty on a public repository
+pub async fn get_bounty(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Response {
+    match public_bounty(&state, &id).await {
+        Some(bounty) => cached_json(&headers, &PublicBounty::from(bounty)),
+        None => (StatusCode::NOT_FOUND, "Bounty not found").into_response(),
+    }
+}
+
+/// Where the funds of an open bounty on a public repository sit, for anyone to verify
+pub async fn onchain(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Response {
+    let Some(bounty) = public_bounty(&state, &id).await else {
+        return (StatusCode::NOT_FOUND, "Bounty not found").into_response();
+    };
+
+    match onchain_state(&state, &bounty).await {
+        Ok(onchain) => cached_json(&headers, &onchain),
+        Err(err) => err.into_response(),
+    }
+}
+
+/// Bounty that can be shown publicly: open, on a public repository and with its issue still
+/// reachable
+async fn public_bounty(state: &AppState, id: &str) -> Option<Bounty> {
+    let bounty: Option<Bounty> = state.db_conn.select(("Bounty", id)).await.unwrap();
+
+    bounty.filter(is_public)
+}
+
+fn is_public(bounty: &Bounty) -> bool {
+    !bounty.private
+        && !bounty.issue_deleted
+        && !bounty.frozen
+        && bounty.status == BountyStatus::Open
+}
+
+/// Serialize a response with caching headers, answering with `304 Not Modified` if the client
+/// already has the current version
+pub(crate) fn cached_json<T: Serialize>(headers: &HeaderMap, value: &T) -> Response {
+    let body = serde_json::to_vec(value).expect("Couldn't serialize response");
+    cached(headers, "application/json", body)
+}
+
+/// Respond with `body` and caching headers, or `304 Not Modified` if the client already has it
+pub(crate) fn cached(headers: &HeaderMap, content_type: &'static str, body: Vec<u8>) -> Response {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    let etag = format!("W/\"{:x}\"", hasher.finish());
+
+    let cache_control = format!("public, max-age={MAX_AGE}, s-maxage={SHARED_MAX_AGE}");
+
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(',')
+                .any(|tag| tag.trim() == etag || tag.trim() == "*")
+        });
+    if not_modified {
+        return (
+            StatusCode::NOT_MODIFIED,
+            [(header::CACHE_CONTROL, cache_control), (header::ETAG, etag)],
+        )
+            .into_response();
+    }
+
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, String::from(content_type)),
+            (header::CACHE_CONTROL, cache_control),
+            (header::ETAG, etag),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+
+    use super::{cached, is_public};
+    use crate::models::{Bounty, BountyStatus};
+
+    #[test]
+    fn only_open_bounties_on_reachable_public_issues_are_public() {
+        assert!(is_public(&Bounty::example()));
+
+        let hidden = [
+            Bounty {
+                private: true,
+                ..Bounty::example()
+            },
+            Bounty {
+                issue_deleted: true,
+                ..Bounty::example()
+            },
+            Bounty {
+                frozen: true,
+                ..Bounty::example()
+            },
+            Bounty {
+                status: BountyStatus::Completed,
+                ..Bounty::example()
+            },
+        ];
+        for bounty in hidden {
+            assert!(!is_public(&bounty), "{bounty:?} should be hidden");
+        }
+    }
+
+    #[test]
+    fn cached_responses_revalidate_with_etag() {
+        let body = b"{}".to_vec();
+        let res = cached(&HeaderMap::new(), "application/json", body.clone());
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers()[header::CACHE_CONTROL]
+            .to_str()
+            .unwrap()
+            .starts_with("public"));
+        let etag = res.headers()[header::ETAG].clone();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::IF_NONE_MATCH,
+            HeaderValue::from_str(&format!("W/\"other\", {}", etag.to_str().unwrap())).unwrap(),
+        );
+        let res = cached(&headers, "application/json", body.clone());
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()[header::ETAG], etag);
+
+        // a changed body doesn't match the old tag
+        let res = cached(&headers, "application/json", b"[]".to_vec());
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,434 @@
+//! Aggregated statistics about bounties and the users that earn them
+//!
+//! Amounts of different assets don't add up, so totals are kept per asset.
+
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Json, Path, Query, State},
+    routing::get,
+    Extension, Router,
+};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use super::bounty::manages_repo;
+use crate::{
+    amount::parse_asset_token,
+    models::{Address, Amount, Asset, Bounty, BountyStatus, Payout},
+    session_auth::{AuthUser, MyRequireAuthorizationLayer},
+    AppState,
+};
+
+/// Default number of entries on the leaderboard
+const DEFAULT_LEADERBOARD_SIZE: usize = 10;
+/// Upper bound on the number of leaderboard entries a client can request
+const MAX_LEADERBOARD_SIZE: usize = 100;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/leaderboard",
+            get(leaderboard).layer(MyRequireAuthorizationLayer::login()),
+        )
+        .route(
+            "/user/:username",
+            get(user_stats).layer(MyRequireAuthorizationLayer::login()),
+        )
+        .route(
+            "/repo/:owner/:repo",
+            get(repo_stats).layer(MyRequireAuthorizationLayer::login()),
+        )
+}
+
+/// Time range statistics are computed over
+#[derive(Debug, Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeWindow {
+    Week,
+    Month,
+    #[default]
+    All,
+}
+
+impl TimeWindow {
+    /// Start of the window, `None` if it covers all time
+    pub fn since(&self) -> Option<chrono::DateTime<chrono::offset::Utc>> {
+        let now = chrono::offset::Utc::now();
+        match self {
+            TimeWindow::Week => Some(now - chrono::Duration::weeks(1)),
+            TimeWindow::Month => Some(now - chrono::Duration::days(30)),
+            TimeWindow::All => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum RankBy {
+    #[default]
+    Earnings,
+    Count,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WindowQuery {
+    #[serde(default)]
+    pub window: TimeWindow,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LeaderboardQuery {
+    #[serde(default)]
+    pub window: TimeWindow,
+    #[serde(default)]
+    pub rank_by: RankBy,
+    /// Asset earnings are ranked in, `ETH` or an ERC-20 token address. Defaults to ETH.
+    pub asset: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// Sum of amounts in a single asset
+#[derive(Debug, Serialize, Clone)]
+pub struct AssetTotal {
+    pub asset: Asset,
+    pub amount: Amount,
+}
+
+/// Add an amount to the total of its asset
+fn add_to_totals(totals: &mut Vec<AssetTotal>, asset: &Asset, amount: Amount) {
+    match totals
+        .iter_mut()
+        .find(|total| total.asset.token == asset.token)
+    {
+        Some(total) => total.amount = total.amount.saturating_add(amount),
+        None => totals.push(AssetTotal {
+            asset: asset.clone(),
+            amount,
+        }),
+    }
+}
+
+#[derive(Debug, Serialize, Default)]
+pub struct UserStats {
+    pub username: String,
+    pub bounties_completed: usize,
+    pub total_earned: Vec<AssetTotal>,
+    /// Average time between a bounty being created and paid out, in seconds
+    pub average_time_to_close: Option<i64>,
+}
+
+impl UserStats {
+    /// Earnings in the asset of `token`
+    fn earned(&self, token: Option<Address>) -> Amount {
+        self.total_earned
+            .iter()
+            .find(|total| total.asset.token == token)
+            .map(|total| total.amount)
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Serialize, Default)]
+pub struct RepoStats {
+    pub owner: String,
+    pub repo: String,
+    pub bounties_funded: usize,
+    pub total_funded: Vec<AssetTotal>,
+    pub bounties_paid: usize,
+    pub total_paid: Vec<AssetTotal>,
+    /// Bounties still holding escrowed funds, either open or claimed by a closer that hasn't
+    /// registered yet
+    pub bounties_open: usize,
+    pub open_value: Vec<AssetTotal>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LeaderboardEntry {
+    pub rank: usize,
+    #[serde(flatten)]
+    pub stats: UserStats,
+}
+
+/// Fetch payouts made within a time window, optionally restricted by an extra condition
+async fn payouts_in_window(
+    state: &AppState,
+    window: TimeWindow,
+    condition: Option<&str>,
+    bindings: Vec<(&'static str, String)>,
+) -> Vec<Payout> {
+    let mut conditions = vec![];
+    if window.since().is_some() {
+        conditions.push("paid >= $since");
+    }
+    if let Some(condition) = condition {
+        conditions.push(condition);
+    }
+
+    let mut sql = String::from("SELECT * FROM Payouts");
+    if !conditions.is_empty() {
+        sql.push_str(" WHERE ");
+        sql.push_str(&conditions.join(" AND "));
+    }
+
+    let mut query = state.db_conn.query(sql).bind(("since", window.since()));
+    for binding in bindings {
+        query = query.bind(binding);
+    }
+
+    let mut res = query.await.unwrap();
+    res.take(0).unwrap()
+}
+
+/// Aggregate payouts per recipient
+fn aggregate_users(payouts: &[Payout]) -> Vec<UserStats> {
+    let mut per_user: HashMap<&str, (UserStats, i64)> = HashMap::new();
+    for payout in payouts.iter() {
+        let (stats, total_seconds) =
+            per_user
+                .entry(payout.recipient.as_str())
+                .or_insert_with(|| {
+                    (
+                        UserStats {
+                            username: payout.recipient.clone(),
+                            ..Default::default()
+                        },
+                        0,
+                    )
+                });
+        stats.bounties_completed += 1;
+        add_to_totals(&mut stats.total_earned, &payout.asset, payout.reward);
+        *total_seconds += (payout.paid - payout.bounty_created).num_seconds();
+    }
+
+    per_user
+        .into_values()
+        .map(|(mut stats, total_seconds)| {
+            stats.average_time_to_close = Some(total_seconds / stats.bounties_completed as i64);
+            stats
+        })
+        .collect()
+}
+
+/// Earnings statistics for a single user
+pub async fn user_stats(
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+    Query(params): Query<WindowQuery>,
+) -> Json<UserStats> {
+    Json(user_totals(&state, username, params.window).await)
+}
+
+/// Compute earnings of a single user over a time window
+pub async fn user_totals(state: &AppState, username: String, window: TimeWindow) -> UserStats {
+    let payouts = payouts_in_window(
+        state,
+        window,
+        Some("recipient == $username"),
+        vec![("username", username.clone())],
+    )
+    .await;
+
+    aggregate_users(&payouts)
+        .pop()
+        .unwrap_or_else(|| UserStats {
+            username,
+            ..Default::default()
+        })
+}
+
+/// Funding and payout statistics for a single repository. Bounties on private repositories only
+/// count for users that manage the app installation on the repository.
+pub async fn repo_stats(
+    State(state): State<AppState>,
+    Path((owner, repo)): Path<(String, String)>,
+    Query(params): Query<WindowQuery>,
+    Extension(auth_user): Extension<AuthUser>,
+) -> Json<RepoStats> {
+    let since = params.window.since();
+    let manager = manages_repo(&state, &auth_user.id, &owner, &repo).await;
+
+    let mut sql =
+        String::from("SELECT * FROM Bounty WHERE issue.owner == $owner AND issue.repo == $repo");
+    if since.is_some() {
+        sql.push_str(" AND created >= $since");
+    }
+    if !manager {
+        sql.push_str(" AND private == false");
+    }
+    let mut res = state
+        .db_conn
+        .query(sql)
+        .bind(("owner", &owner))
+        .bind(("repo", &repo))
+        .bind(("since", since))
+        .await
+        .unwrap();
+    let bounties: Vec<Bounty> = res.take(0).unwrap();
+
+    let payout_condition = if manager {
+        "issue.owner == $owner AND issue.repo == $repo"
+    } else {
+        "issue.owner == $owner AND issue.repo == $repo AND bounty.private == false"
+    };
+    let payouts = payouts_in_window(
+        &state,
+        params.window,
+        Some(payout_condition),
+        vec![("owner", owner.clone()), ("repo", repo.clone())],
+    )
+    .await;
+
+    let mut stats = RepoStats {
+        owner,
+        repo,
+        ..Default::default()
+    };
+    for bounty in bounties.iter() {
+        stats.bounties_funded += 1;
+        add_to_totals(&mut stats.total_funded, &bounty.asset, bounty.reward);
+        if matches!(bounty.status, BountyStatus::Open | BountyStatus::Claimed) {
+            stats.bounties_open += 1;
+            add_to_totals(&mut stats.open_value, &bounty.asset, bounty.reward);
+        }
+    }
+    for payout in payouts.iter() {
+        stats.bounties_paid += 1;
+        add_to_totals(&mut stats.total_paid, &payout.asset, payout.reward);
+    }
+
+    Json(stats)
+}
+
+/// Sort users best first, ties are broken by the other criterion and then by username. Earnings
+/// are compared in the asset of `token`.
+fn rank_users(users: &mut [UserStats], rank_by: RankBy, token: Option<Address>) {
+    users.sort_by(|a, b| {
+        let earnings = b.earned(token).cmp(&a.earned(token));
+        let count = b.bounties_completed.cmp(&a.bounties_completed);
+        match rank_by {
+            RankBy::Earnings => earnings.then(count),
+            RankBy::Count => count.then(earnings),
+        }
+        .then_with(|| a.username.cmp(&b.username))
+    });
+}
+
+/// Users ranked by earnings in one asset or by number of completed bounties
+pub async fn leaderboard(
+    State(state): State<AppState>,
+    Query(params): Query<LeaderboardQuery>,
+) -> Result<Json<Vec<LeaderboardEntry>>, (StatusCode, String)> {
+    let token = match params.asset.as_deref() {
+        Some(asset) => parse_asset_token(asset).map_err(|err| (StatusCode::BAD_REQUEST, err))?,
+        None => None,
+    };
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_LEADERBOARD_SIZE)
+        .clamp(1, MAX_LEADERBOARD_SIZE);
+
+    let payouts = payouts_in_window(&state, params.window, None, vec![]).await;
+    let mut users = aggregate_users(&payouts);
+    rank_users(&mut users, params.rank_by, token);
+
+    let entries = users
+        .into_iter()
+        .take(limit)
+        .enumerate()
+        .map(|(i, stats)| LeaderboardEntry { rank: i + 1, stats })
+        .collect();
+
+    Ok(Json(entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use surrealdb::sql::Thing;
+
+    use super::{aggregate_users, rank_users, RankBy, UserStats};
+    use crate::models::{Address, Amount, Asset, Issue, Payout};
+
+    fn payout(recipient: &str, reward: u64, token: Option<Address>, hours_open: i64) -> Payout {
+        let bounty_created: chrono::DateTime<chrono::offset::Utc> =
+            "2023-05-01T12:00:00Z".parse().unwrap();
+        Payout {
+            bounty: Thing::from(("Bounty", "example")),
+            recipient: recipient.into(),
+            wallet_address: Address::zero(),
+            reward: Amount::from(reward),
+            asset: Asset {
+                token,
+                ..Asset::native()
+            },
+            issue: Issue {
+                owner: "gitbounties".into(),
+                repo: "backend".into(),
+                issue_id: 1,
+            },
+            bounty_created,
+            paid: bounty_created + chrono::Duration::hours(hours_open),
+        }
+    }
+
+    fn user<'a>(users: &'a [UserStats], username: &str) -> &'a UserStats {
+        users.iter().find(|user| user.username == username).unwrap()
+    }
+
+    #[test]
+    fn aggregate_keeps_totals_per_asset() {
+        let token = Some(Address::repeat_byte(1));
+        let users = aggregate_users(&[
+            payout("alice", 10, None, 1),
+            payout("alice", 5, None, 3),
+            payout("alice", 7, token, 5),
+            payout("bob", 1, None, 24),
+        ]);
+
+        assert_eq!(users.len(), 2);
+        let alice = user(&users, "alice");
+        assert_eq!(alice.bounties_completed, 3);
+        assert_eq!(alice.earned(None), Amount::from(15));
+        assert_eq!(alice.earned(token), Amount::from(7));
+        assert_eq!(alice.total_earned.len(), 2);
+        assert_eq!(alice.average_time_to_close, Some(3 * 3600));
+
+        let bob = user(&users, "bob");
+        assert_eq!(bob.earned(token), Amount::default());
+        assert_eq!(bob.average_time_to_close, Some(24 * 3600));
+    }
+
+    #[test]
+    fn rank_by_earnings_or_count() {
+        let token = Some(Address::repeat_byte(1));
+        let mut users = aggregate_users(&[
+            // alice earned the most ETH in a single bounty
+            payout("alice", 100, None, 1),
+            // bob completed the most bounties
+            payout("bob", 10, None, 1),
+            payout("bob", 10, None, 1),
+            payout("bob", 10, token, 1),
+            // carol and dave tie on both, and earned the most of the token
+            payout("carol", 20, None, 1),
+            payout("carol", 50, token, 1),
+            payout("dave", 20, None, 1),
+            payout("dave", 50, token, 1),
+        ]);
+        let order = |users: &[UserStats]| {
+            users
+                .iter()
+                .map(|user| user.username.clone())
+                .collect::<Vec<_>>()
+        };
+
+        rank_users(&mut users, RankBy::Earnings, None);
+        assert_eq!(order(&users), ["alice", "bob", "carol", "dave"]);
+
+        rank_users(&mut users, RankBy::Earnings, token);
+        assert_eq!(order(&users), ["carol", "dave", "bob", "alice"]);
+
+        // equal counts fall back to earnings
+        rank_users(&mut users, RankBy::Count, None);
+        assert_eq!(order(&users), ["bob", "carol", "dave", "alice"]);
+    }
+}
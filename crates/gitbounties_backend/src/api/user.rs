@@ -0,0 +1,402 @@
+use axum::{
+    extract::{Json, Path, State},
+    routing::{get, patch, put},
+    Extension, Router,
+};
+use gitbounties_contract::parse_address;
+use log::{debug, info};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use super::stats::{user_totals, TimeWindow, UserStats};
+use crate::{
+    models::{Address, User, UserWallet, WalletChange},
+    payout::unsettled_jobs_to,
+    session_auth::{AuthUser, MyRequireAuthorizationLayer},
+    wallet, AppState,
+};
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/me",
+            get(me)
+                .patch(update_me)
+                .layer(MyRequireAuthorizationLayer::login()),
+        )
+        .route(
+            "/me/wallets",
+            get(list_wallets)
+                .post(create_wallet)
+                .layer(MyRequireAuthorizationLayer::login()),
+        )
+        .route(
+            "/me/wallets/:address",
+            patch(update_wallet)
+                .delete(remove_wallet)
+                .layer(MyRequireAuthorizationLayer::login()),
+        )
+        .route(
+            "/me/wallets/:address/payout",
+            put(make_payout_wallet).layer(MyRequireAuthorizationLayer::login()),
+        )
+        .route("/:username", get(profile))
+}
+
+/// Get the logged in user
+pub async fn me(
+    State(state): State<AppState>,
+    Extension(auth_user): Extension<AuthUser>,
+) -> Result<Json<User>, (StatusCode, &'static str)> {
+    get_user(&state, &auth_user.id).await.map(Json)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifiedWalletBody {
+    /// Wallet to add
+    pub wallet_address: String,
+    /// Nonce issued by `/auth/nonce` for the wallet
+    pub nonce: String,
+    /// Signature of the ownership message by the wallet
+    pub signature: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UpdateMeResponse {
+    pub user: User,
+    /// Wallet that was replaced, so the client can confirm the change to the user
+    pub previous_wallet_address: Address,
+    /// Bounties whose payout was already queued to the previous wallet. Queued payouts are not
+    /// redirected, they still go to `previous_wallet_address`.
+    pub unsettled_payouts: Vec<String>,
+}
+
+/// Update the logged in user's payout wallet, adding it to their wallets if needed
+pub async fn update_me(
+    State(state): State<AppState>,
+    Extension(auth_user): Extension<AuthUser>,
+    Json(payload): Json<VerifiedWalletBody>,
+) -> Result<Json<UpdateMeResponse>, (StatusCode, &'static str)> {
+    let Ok(wallet_address) = parse_address(&payload.wallet_address) else {
+        return Err((StatusCode::BAD_REQUEST, "invalid wallet address"));
+    };
+
+    let mut user_data = get_user(&state, &auth_user.id).await?;
+
+    wallet::verify_ownership(&state, &wallet_address, &payload.nonce, &payload.signature).await?;
+
+    add_wallet(&mut user_data, wallet_address, None, None);
+    let previous_wallet_address = set_payout_wallet(&state, &mut user_data, wallet_address).await;
+    save_wallets(&state, &user_data).await;
+
+    let unsettled_payouts =
+        unsettled_payouts(&state, previous_wallet_address, wallet_address).await;
+    Ok(Json(UpdateMeResponse {
+        user: user_data,
+        previous_wallet_address,
+        unsettled_payouts,
+    }))
+}
+
+/// List the logged in user's wallets
+pub async fn list_wallets(
+    State(state): State<AppState>,
+    Extension(auth_user): Extension<AuthUser>,
+) -> Result<Json<Vec<UserWallet>>, (StatusCode, &'static str)> {
+    let user_data = get_user(&state, &auth_user.id).await?;
+
+    Ok(Json(user_data.active_wallets().cloned().collect()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddWalletBody {
+    #[serde(flatten)]
+    pub verification: VerifiedWalletBody,
+    pub label: Option<String>,
+    pub chain_id: Option<u64>,
+    /// Also make this the wallet payouts are sent to
+    #[serde(default)]
+    pub payout: bool,
+}
+
+/// Add a wallet the user has proven ownership of
+pub async fn create_wallet(
+    State(state): State<AppState>,
+    Extension(auth_user): Extension<AuthUser>,
+    Json(payload): Json<AddWalletBody>,
+) -> Result<Json<User>, (StatusCode, &'static str)> {
+    let Ok(wallet_address) = parse_address(&payload.verification.wallet_address) else {
+        return Err((StatusCode::BAD_REQUEST, "invalid wallet address"));
+    };
+
+    let mut user_data = get_user(&state, &auth_user.id).await?;
+
+    wallet::verify_ownership(
+        &state,
+        &wallet_address,
+        &payload.verification.nonce,
+        &payload.verification.signature,
+    )
+    .await?;
+
+    add_wallet(
+        &mut user_data,
+        wallet_address,
+        payload.label,
+        payload.chain_id,
+    );
+    if payload.payout {
+        set_payout_wallet(&state, &mut user_data, wallet_address).await;
+    }
+    save_wallets(&state, &user_data).await;
+
+    Ok(Json(user_data))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateWalletBody {
+    pub label: Option<String>,
+    pub chain_id: Option<u64>,
+}
+
+/// Change the label or chain of a wallet
+pub async fn update_wallet(
+    State(state): State<AppState>,
+    Extension(auth_user): Extension<AuthUser>,
+    Path(address): Path<String>,
+    Json(payload): Json<UpdateWalletBody>,
+) -> Result<Json<User>, (StatusCode, &'static str)> {
+    let Ok(wallet_address) = parse_address(&address) else {
+        return Err((StatusCode::BAD_REQUEST, "invalid wallet address"));
+    };
+
+    let mut user_data = get_user(&state, &auth_user.id).await?;
+
+    let Some(wallet) = user_data
+        .wallets
+        .iter_mut()
+        .find(|wallet| wallet.address == wallet_address && wallet.removed.is_none())
+    else {
+        return Err((StatusCode::NOT_FOUND, "wallet does not exist"));
+    };
+    wallet.label = payload.label;
+    wallet.chain_id = payload.chain_id;
+    save_wallets(&state, &user_data).await;
+
+    Ok(Json(user_data))
+}
+
+/// Make one of the user's wallets the payout wallet
+pub async fn make_payout_wallet(
+    State(state): State<AppState>,
+    Extension(auth_user): Extension<AuthUser>,
+    Path(address): Path<String>,
+) -> Result<Json<UpdateMeResponse>, (StatusCode, &'static str)> {
+    let Ok(wallet_address) = parse_address(&address) else {
+        return Err((StatusCode::BAD_REQUEST, "invalid wallet address"));
+    };
+
+    let mut user_data = get_user(&state, &auth_user.id).await?;
+
+    if !user_data
+        .active_wallets()
+        .any(|wallet| wallet.address == wallet_address)
+    {
+        return Err((StatusCode::NOT_FOUND, "wallet does not exist"));
+    }
+
+    let previous_wallet_address = set_payout_wallet(&state, &mut user_data, wallet_address).await;
+    save_wallets(&state, &user_data).await;
+
+    let unsettled_payouts =
+        unsettled_payouts(&state, previous_wallet_address, wallet_address).await;
+    Ok(Json(UpdateMeResponse {
+        user: user_data,
+        previous_wallet_address,
+        unsettled_payouts,
+    }))
+}
+
+/// Remove a wallet. The payout wallet can't be removed until another one is designated.
+pub async fn remove_wallet(
+    State(state): State<AppState>,
+    Extension(auth_user): Extension<AuthUser>,
+    Path(address): Path<String>,
+) -> Result<Json<User>, (StatusCode, &'static str)> {
+    let Ok(wallet_address) = parse_address(&address) else {
+        return Err((StatusCode::BAD_REQUEST, "invalid wallet address"));
+    };
+
+    let mut user_data = get_user(&state, &auth_user.id).await?;
+
+    if user_data.payout_wallet() == wallet_address {
+        return Err((StatusCode::CONFLICT, "can't remove the payout wallet"));
+    }
+
+    let Some(wallet) = user_data
+        .wallets
+        .iter_mut()
+        .find(|wallet| wallet.address == wallet_address && wallet.removed.is_none())
+    else {
+        return Err((StatusCode::NOT_FOUND, "wallet does not exist"));
+    };
+    wallet.removed = Some(chrono::offset::Utc::now());
+    save_wallets(&state, &user_data).await;
+
+    Ok(Json(user_data))
+}
+
+#[derive(Debug, Serialize)]
+pub struct Profile {
+    pub username: String,
+    pub wallet_address: Address,
+    pub stats: UserStats,
+}
+
+/// Public profile of a user
+pub async fn profile(
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+) -> Result<Json<Profile>, (StatusCode, &'static str)> {
+    let user_data = get_user(&state, &username).await?;
+
+    let stats = user_totals(&state, user_data.username.clone(), TimeWindow::All).await;
+
+    debug!("profile for {username}");
+
+    Ok(Json(Profile {
+        username: user_data.username,
+        wallet_address: user_data.wallet_address,
+        stats,
+    }))
+}
+
+async fn get_user(state: &AppState, username: &str) -> Result<User, (StatusCode, &'static str)> {
+    let user_data: Option<User> = state.db_conn.select(("Users", username)).await.unwrap();
+    let mut user_data = user_data.ok_or((StatusCode::NOT_FOUND, "user does not exist"))?;
+
+    // users registered before multiple wallets were supported only have their payout wallet
+    if user_data.wallets.is_empty() {
+        let address = user_data.wallet_address;
+        add_wallet(&mut user_data, address, None, None);
+    }
+
+    Ok(user_data)
+}
+
+/// Add a verified wallet to the user, doing nothing if it is already an active wallet
+fn add_wallet(
+    user_data: &mut User,
+    address: Address,
+    label: Option<String>,
+    chain_id: Option<u64>,
+) {
+    if user_data
+        .active_wallets()
+        .any(|wallet| wallet.address == address)
+    {
+        return;
+    }
+    user_data.wallets.push(UserWallet {
+        address,
+        label,
+        chain_id,
+        verified: chrono::offset::Utc::now(),
+        removed: None,
+    });
+}
+
+/// Change the payout wallet of a user, returning the previous one
+async fn set_payout_wallet(state: &AppState, user_data: &mut User, address: Address) -> Address {
+    let previous = user_data.payout_wallet();
+    if previous == address {
+        return previous;
+    }
+    user_data.wallet_address = address;
+
+    // keep a trail of wallet changes so payouts can always be traced back
+    let _res: WalletChange = state
+        .db_conn
+        .create("WalletChanges")
+        .content(WalletChange {
+            username: user_data.username.clone(),
+            previous,
+            new: address,
+            changed: chrono::offset::Utc::now(),
+        })
+        .await
+        .unwrap();
+
+    info!(
+        "user {} changed payout wallet from {:?} to {:?}",
+        user_data.username, previous, address
+    );
+
+    previous
+}
+
+/// Bounties still being paid to the previous payout wallet after a change
+async fn unsettled_payouts(state: &AppState, previous: Address, new: Address) -> Vec<String> {
+    if previous == new {
+        return vec![];
+    }
+    unsettled_jobs_to(state, previous)
+        .await
+        .into_iter()
+        .map(|job| job.bounty.id.to_raw())
+        .collect()
+}
+
+async fn save_wallets(state: &AppState, user_data: &User) {
+    state
+        .db_conn
+        .query("UPDATE type::thing('Users', $username) SET wallet_address = $wallet_address, wallets = $wallets")
+        .bind(("username", &user_data.username))
+        .bind(("wallet_address", user_data.wallet_address))
+        .bind(("wallets", &user_data.wallets))
+        .await
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::add_wallet;
+    use crate::models::{Address, User};
+
+    fn user(wallet_address: Address) -> User {
+        User {
+            username: "alice".into(),
+            github_installations: vec![],
+            wallet_address,
+            wallets: vec![],
+        }
+    }
+
+    #[test]
+    fn wallets_are_added_once_and_can_be_added_back_after_removal() {
+        let payout = Address::repeat_byte(1);
+        let other = Address::repeat_byte(2);
+        let mut user_data = user(payout);
+
+        add_wallet(&mut user_data, payout, None, None);
+        add_wallet(&mut user_data, other, Some("cold".into()), Some(10));
+        // adding an active wallet again keeps its label and chain
+        add_wallet(&mut user_data, other, None, None);
+        assert_eq!(user_data.wallets.len(), 2);
+        assert_eq!(user_data.wallets[1].label.as_deref(), Some("cold"));
+        assert_eq!(user_data.wallets[1].chain_id, Some(10));
+
+        user_data.wallets[1].removed = Some(chrono::offset::Utc::now());
+        let active: Vec<_> = user_data
+            .active_wallets()
+            .map(|wallet| wallet.address)
+            .collect();
+        assert_eq!(active, vec![payout]);
+
+        // removed wallets stay in the history and a new entry is added
+        add_wallet(&mut user_data, other, None, None);
+        assert_eq!(user_data.wallets.len(), 3);
+        assert_eq!(user_data.active_wallets().count(), 2);
+        assert_eq!(user_data.payout_wallet(), payout);
+    }
+}
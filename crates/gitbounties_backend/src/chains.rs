@@ -0,0 +1,181 @@
+//! Chains bounties can be escrowed on
+//!
+//! A single chain is configured with the plain `CHAIN_*`/`CONTRACT_ADDRESS` env vars. To support
+//! several, list names in `CHAINS` (e.g. `CHAINS=mainnet,base`) and configure each one with env
+//! vars prefixed by its upper cased name (`MAINNET_CHAIN_RPC_URL`, `BASE_CONTRACT_ADDRESS`, ...).
+//! The first chain listed is the default for new bounties.
+//!
+//! Each chain gets one long-lived operator client, signing with the backend chosen by
+//! `OPERATOR_SIGNER`, shared by everything that sends transactions on it.
+
+use std::{collections::HashMap, env};
+
+use anyhow::anyhow;
+use gitbounties_contract::{ChainConfig, Operator, OperatorSigner};
+use log::info;
+use surrealdb::sql::Value;
+
+use crate::{db::DBConnection, models::Bounty};
+
+#[derive(Debug)]
+pub struct ChainRegistry {
+    default_chain_id: u64,
+    chains: HashMap<u64, ChainConfig>,
+    operators: HashMap<u64, Operator>,
+}
+
+impl ChainRegistry {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let names = env::var("CHAINS").unwrap_or_default();
+        let names = names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect::<Vec<_>>();
+
+        let configs = if names.is_empty() {
+            vec![ChainConfig::from_env()?]
+        } else {
+            names
+                .iter()
+                .map(|name| ChainConfig::from_env_prefixed(&format!("{}_", name.to_uppercase())))
+                .collect::<anyhow::Result<Vec<_>>>()?
+        };
+
+        let signer = OperatorSigner::from_env()?;
+
+        let default_chain_id = configs[0].chain_id;
+        let mut chains = HashMap::new();
+        let mut operators = HashMap::new();
+        for config in configs {
+            let chain_id = config.chain_id;
+            operators.insert(chain_id, Operator::new(&config, signer.clone())?);
+            if chains.insert(chain_id, config).is_some() {
+                return Err(anyhow!("chain {chain_id} is configured more than once"));
+            }
+        }
+
+        info!(
+            "Loaded {} chains, default chain is {}",
+            chains.len(),
+            default_chain_id
+        );
+
+        Ok(ChainRegistry {
+            default_chain_id,
+            chains,
+            operators,
+        })
+    }
+
+    /// Make sure every rpc endpoint serves the chain it is configured for
+    pub async fn validate(&self) -> anyhow::Result<()> {
+        for chain in self.chains.values() {
+            chain.validate().await?;
+        }
+        Ok(())
+    }
+
+    pub fn default_chain(&self) -> &ChainConfig {
+        &self.chains[&self.default_chain_id]
+    }
+
+    /// Chain to use for a request, falling back to the default chain when none is asked for
+    pub fn get(&self, chain_id: Option<u64>) -> Option<&ChainConfig> {
+        self.chains.get(&chain_id.unwrap_or(self.default_chain_id))
+    }
+
+    /// Client sending transactions from the operator wallet on a chain, if it is configured
+    pub fn operator(&self, chain_id: u64) -> Option<&Operator> {
+        self.operators.get(&chain_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ChainConfig> {
+        self.chains.values()
+    }
+
+    /// Chain and contract a bounty's token lives on, unless the chain is no longer configured
+    pub fn for_bounty(&self, bounty: &Bounty) -> Option<ChainConfig> {
+        let chain = self.chains.get(&bounty.chain_id)?;
+
+        // the contract may have been redeployed since the bounty was created
+        Some(ChainConfig {
+            contract_address: bounty.contract_address,
+            ..chain.clone()
+        })
+    }
+
+    /// Assign bounties created before multi-chain support to the default chain
+    pub async fn backfill_bounties(&self, db_conn: &DBConnection) {
+        let chain = self.default_chain();
+        let mut res = db_conn
+            .query("UPDATE Bounty SET chain_id = $chain_id, contract_address = $contract_address WHERE chain_id == NONE")
+            .bind(("chain_id", chain.chain_id))
+            .bind(("contract_address", chain.contract_address))
+            .await
+            .expect("Failed to assign bounties to the default chain");
+        let _: Value = res
+            .take(0)
+            .expect("Failed to assign bounties to the default chain");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use gitbounties_contract::{ChainConfig, GasPolicy};
+
+    use super::ChainRegistry;
+    use crate::models::{Address, Bounty};
+
+    fn chain(chain_id: u64) -> ChainConfig {
+        ChainConfig {
+            rpc_http: "http://127.0.0.1:8545".into(),
+            rpc_ws: None,
+            chain_id,
+            contract_address: Address::repeat_byte(chain_id as u8),
+            confirmations: 6,
+            reward_tokens: vec![],
+            gas: GasPolicy::default(),
+        }
+    }
+
+    fn registry() -> ChainRegistry {
+        ChainRegistry {
+            default_chain_id: 8453,
+            chains: HashMap::from([(1, chain(1)), (8453, chain(8453))]),
+            operators: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn requests_default_to_the_first_chain() {
+        let chains = registry();
+
+        assert_eq!(chains.default_chain().chain_id, 8453);
+        assert_eq!(chains.get(None).unwrap().chain_id, 8453);
+        assert_eq!(chains.get(Some(1)).unwrap().chain_id, 1);
+        assert!(chains.get(Some(10)).is_none());
+    }
+
+    #[test]
+    fn bounties_keep_the_contract_they_were_minted_by() {
+        let chains = registry();
+
+        let bounty = Bounty {
+            chain_id: 1,
+            contract_address: Address::repeat_byte(0xaa),
+            ..Bounty::example()
+        };
+        let chain = chains.for_bounty(&bounty).unwrap();
+        assert_eq!(chain.chain_id, 1);
+        assert_eq!(chain.contract_address, Address::repeat_byte(0xaa));
+
+        let unconfigured = Bounty {
+            chain_id: 10,
+            ..Bounty::example()
+        };
+        assert!(chains.for_bounty(&unconfigured).is_none());
+    }
+}
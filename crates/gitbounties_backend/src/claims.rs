@@ -0,0 +1,245 @@
+//! Bounties won by users that haven't registered yet
+//!
+//! The reward stays in escrow under a pending claim for the github user that closed the issue. It
+//! is paid out once they register with a verified wallet, or refunded to the bounty owner if they
+//! don't register before the claim times out.
+
+use std::{env, time::Duration};
+
+use log::{debug, info};
+
+use crate::{
+    models::{Address, Bounty, ClaimStatus, PayoutJob, PayoutJobKind, PendingClaim},
+    notify::{notify_bounty_event, BountyEvent},
+    payout::{active_job, pay_bounty, refund_bounty},
+    AppState,
+};
+
+/// Default number of days before an unclaimed reward is refunded
+const DEFAULT_CLAIM_TIMEOUT_DAYS: i64 = 30;
+/// How often to look for expired claims
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long a claim stays open, configured with `CLAIM_TIMEOUT_DAYS`
+pub fn claim_timeout() -> chrono::Duration {
+    let days = env::var("CLAIM_TIMEOUT_DAYS")
+        .ok()
+        .map(|days| {
+            days.parse::<i64>()
+                .expect("CLAIM_TIMEOUT_DAYS should be a number")
+        })
+        .unwrap_or(DEFAULT_CLAIM_TIMEOUT_DAYS);
+    chrono::Duration::days(days)
+}
+
+/// Hold the reward of a bounty for a github user that isn't registered
+pub async fn create_claim(
+    state: &AppState,
+    bounty: &Bounty,
+    github_login: &str,
+    pull_request: u64,
+) -> PendingClaim {
+    let bounty_id = bounty
+        .id
+        .clone()
+        .expect("Bounty read from database should have id");
+
+    state
+        .db_conn
+        .query("UPDATE $bounty SET status = 'Claimed'")
+        .bind(("bounty", &bounty_id))
+        .await
+        .unwrap();
+
+    let now = chrono::offset::Utc::now();
+    let claim: PendingClaim = state
+        .db_conn
+        .create("PendingClaims")
+        .content(PendingClaim {
+            id: None,
+            bounty: bounty_id,
+            github_login: github_login.to_string(),
+            pull_request,
+            created: now,
+            expires: now + state.claim_timeout,
+            status: ClaimStatus::Pending,
+        })
+        .await
+        .unwrap();
+
+    info!(
+        "holding bounty {}/{}/{} for unregistered user {}",
+        bounty.issue.owner, bounty.issue.repo, bounty.issue.issue_id, github_login
+    );
+
+    notify_bounty_event(
+        state,
+        bounty,
+        BountyEvent::Claimed {
+            github_login: github_login.to_string(),
+        },
+    )
+    .await;
+
+    claim
+}
+
+/// Pay out every pending claim of a user that just registered
+pub async fn settle_claims(state: &AppState, username: &str, wallet_address: &Address) {
+    let mut res = state
+        .db_conn
+        .query("SELECT * FROM PendingClaims WHERE github_login == $login AND status == 'Pending'")
+        .bind(("login", username))
+        .await
+        .unwrap();
+    let claims: Vec<PendingClaim> = res.take(0).unwrap();
+
+    for claim in claims {
+        let bounty: Bounty = state
+            .db_conn
+            .select(("Bounty", claim.bounty.id.to_raw().as_str()))
+            .await
+            .expect("Claimed bounty should exist in database");
+
+        if pay_bounty(state, &bounty, username, wallet_address).await {
+            set_claim_status(state, &claim, ClaimStatus::Paid).await;
+            continue;
+        }
+
+        // the funds are already being released, or the issue was deleted and the bounty can only
+        // be refunded
+        let status = match active_job(state, &claim.bounty).await {
+            Some(job) => released_claim_status(&job, &claim),
+            None => {
+                if !refund_bounty(state, &bounty).await {
+                    continue;
+                }
+                ClaimStatus::Refunded
+            },
+        };
+        set_claim_status(state, &claim, status).await;
+    }
+}
+
+/// Refund every claim that has not been settled before its deadline
+pub async fn expire_claims(state: &AppState) {
+    let mut res = state
+        .db_conn
+        .query("SELECT * FROM PendingClaims WHERE status == 'Pending' AND expires < $now")
+        .bind(("now", chrono::offset::Utc::now()))
+        .await
+        .unwrap();
+    let claims: Vec<PendingClaim> = res.take(0).unwrap();
+
+    for claim in claims {
+        let bounty: Bounty = state
+            .db_conn
+            .select(("Bounty", claim.bounty.id.to_raw().as_str()))
+            .await
+            .expect("Claimed bounty should exist in database");
+
+        debug!(
+            "claim by {} expired, refunding {}",
+            claim.github_login, bounty.user
+        );
+
+        if !refund_bounty(state, &bounty).await {
+            if let Some(job) = active_job(state, &claim.bounty).await {
+                set_claim_status(state, &claim, released_claim_status(&job, &claim)).await;
+            }
+            continue;
+        }
+        set_claim_status(state, &claim, ClaimStatus::Refunded).await;
+        notify_bounty_event(state, &bounty, BountyEvent::Expired).await;
+    }
+}
+
+/// Status of a claim whose bounty funds are already being released by another job
+fn released_claim_status(job: &PayoutJob, claim: &PendingClaim) -> ClaimStatus {
+    match &job.kind {
+        PayoutJobKind::Payout { recipient } if *recipient == claim.github_login => {
+            ClaimStatus::Paid
+        },
+        _ => ClaimStatus::Refunded,
+    }
+}
+
+/// Periodically refund expired claims
+pub async fn expire_claims_loop(state: AppState) {
+    let mut interval = tokio::time::interval(EXPIRE_INTERVAL);
+    loop {
+        interval.tick().await;
+        expire_claims(&state).await;
+    }
+}
+
+async fn set_claim_status(state: &AppState, claim: &PendingClaim, status: ClaimStatus) {
+    state
+        .db_conn
+        .query("UPDATE $claim SET status = $status")
+        .bind(("claim", &claim.id))
+        .bind(("status", status))
+        .await
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use surrealdb::sql::Thing;
+
+    use super::released_claim_status;
+    use crate::models::{
+        Address, ClaimStatus, PayoutJob, PayoutJobKind, PayoutJobStatus, PendingClaim,
+    };
+
+    fn job(kind: PayoutJobKind) -> PayoutJob {
+        let now = chrono::offset::Utc::now();
+        PayoutJob {
+            id: None,
+            bounty: Thing::from(("Bounty", "example")),
+            kind,
+            wallet_address: Address::repeat_byte(1),
+            status: PayoutJobStatus::Queued,
+            slot: "active".into(),
+            custody_tx: None,
+            sweep_tx: None,
+            transfer_tx: None,
+            burn_tx: None,
+            attempts: 0,
+            next_attempt: now,
+            last_error: None,
+            created: now,
+        }
+    }
+
+    #[test]
+    fn claims_are_paid_only_by_a_payout_to_their_user() {
+        let now = chrono::offset::Utc::now();
+        let claim = PendingClaim {
+            id: None,
+            bounty: Thing::from(("Bounty", "example")),
+            github_login: "bob".into(),
+            pull_request: 3,
+            created: now,
+            expires: now,
+            status: ClaimStatus::Pending,
+        };
+
+        let paid = job(PayoutJobKind::Payout {
+            recipient: "bob".into(),
+        });
+        assert_eq!(released_claim_status(&paid, &claim), ClaimStatus::Paid);
+
+        let paid_to_other = job(PayoutJobKind::Payout {
+            recipient: "carol".into(),
+        });
+        assert_eq!(
+            released_claim_status(&paid_to_other, &claim),
+            ClaimStatus::Refunded
+        );
+        assert_eq!(
+            released_claim_status(&job(PayoutJobKind::Refund), &claim),
+            ClaimStatus::Refunded
+        );
+    }
+}
@@ -2,10 +2,15 @@ use log::info;
 use surrealdb::{
     engine::remote::ws::{Client, Ws},
     opt::auth::Root,
+    sql::Value,
     Surreal,
 };
 
-use crate::models::{Bounty, BountyStatus, Issue, User};
+use crate::{
+    amount::reward_key,
+    chains::ChainRegistry,
+    models::{Bounty, BountyStatus, Issue, User},
+};
 
 pub type DBConnection = Surreal<Client>;
 
@@ -29,9 +34,77 @@ pub async fn connect(
 
 pub async fn user_register() {}
 
+/// Indexes backing bounty listing filters, payout statistics and uniqueness of tokens and payout
+/// jobs
+const INDEXES: &[&str] = &[
+    "DEFINE INDEX bounty_user ON TABLE Bounty COLUMNS user",
+    "DEFINE INDEX bounty_status ON TABLE Bounty COLUMNS status",
+    "DEFINE INDEX bounty_repo ON TABLE Bounty COLUMNS issue.owner, issue.repo",
+    "DEFINE INDEX bounty_reward ON TABLE Bounty COLUMNS reward_key",
+    "DEFINE INDEX bounty_created ON TABLE Bounty COLUMNS created",
+    "DEFINE INDEX bounty_token ON TABLE Bounty COLUMNS chain_id, contract_address, token_id UNIQUE",
+    "DEFINE INDEX payout_recipient ON TABLE Payouts COLUMNS recipient",
+    "DEFINE INDEX payout_paid ON TABLE Payouts COLUMNS paid",
+    "DEFINE INDEX chain_event_token ON TABLE ChainEvents COLUMNS token_id",
+    "DEFINE INDEX payout_job_active ON TABLE PayoutJobs COLUMNS bounty, slot UNIQUE",
+    "DEFINE INDEX payout_job_status ON TABLE PayoutJobs COLUMNS status, next_attempt",
+];
+
 /// Initialize database
-pub async fn migrate(db_conn: &DBConnection) {
-    // initalize with some dummy data
+///
+/// Older records are backfilled before the indexes are defined, since unique indexes can't be
+/// defined over records that are missing the indexed fields.
+pub async fn migrate(db_conn: &DBConnection, chains: &ChainRegistry) {
+    // rewards used to be stored as integers, store them as decimal strings
+    for statement in [
+        "UPDATE Bounty SET reward = <string> reward",
+        "UPDATE Payouts SET reward = <string> reward",
+        "UPDATE ChainEvents SET amount = <string> amount WHERE amount != NONE",
+    ] {
+        let mut res = db_conn
+            .query(statement)
+            .await
+            .expect("Failed to migrate reward amounts");
+        let _: Value = res.take(0).expect("Failed to migrate reward amounts");
+    }
+
+    // rewards are filtered and sorted on a fixed width key, fill it in for older bounties
+    let mut res = db_conn
+        .query("SELECT * FROM Bounty WHERE reward_key == NONE")
+        .await
+        .expect("Failed to migrate reward keys");
+    let bounties: Vec<Bounty> = res.take(0).expect("Failed to migrate reward keys");
+    for bounty in bounties {
+        let mut res = db_conn
+            .query("UPDATE $bounty SET reward_key = $reward_key")
+            .bind(("bounty", &bounty.id))
+            .bind(("reward_key", reward_key(bounty.asset.token, bounty.reward)))
+            .await
+            .expect("Failed to migrate reward keys");
+        let _: Value = res.take(0).expect("Failed to migrate reward keys");
+    }
+
+    // payout jobs hold a slot on their bounty until they fail, backing the unique active job index
+    let mut res = db_conn
+        .query("UPDATE PayoutJobs SET slot = IF status == 'Failed' THEN <string> id ELSE 'active' END WHERE slot == NONE")
+        .await
+        .expect("Failed to migrate payout jobs");
+    let _: Value = res.take(0).expect("Failed to migrate payout jobs");
+
+    // the unique token index covers the chain of the bounty
+    chains.backfill_bounties(db_conn).await;
+
+    for index in INDEXES {
+        let mut res = db_conn
+            .query(*index)
+            .await
+            .expect("Failed to define indexes");
+        let _: Value = res
+            .take(0)
+            .unwrap_or_else(|err| panic!("Failed to define indexes, {index}: {err}"));
+    }
+
+    info!("Finished database migrations");
 }
 
 pub async fn migrate_dummy(db_conn: &DBConnection) {
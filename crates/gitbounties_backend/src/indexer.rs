@@ -0,0 +1,447 @@
+//! Follow the bounty contract on chain and reconcile bounties with what actually happened
+//!
+//! Activity is only acted upon once it is buried under the chain's configured number of
+//! confirmations, so a reorg can't make us act on events that later disappear. Every configured
+//! chain is indexed separately, with the last indexed block kept in the `ChainCursor` table so the
+//! indexer resumes where it left off after a restart.
+
+use std::{
+    env,
+    time::{Duration, Instant},
+};
+
+use gitbounties_contract::{
+    account_balance, Address, ChainConfig, Middleware, ReadOnlyContract, H256, U256,
+};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amount::reward_key,
+    models::{Amount, Bounty, BountyStatus, ChainEvent, ChainEventKind, User},
+    notify::{notify_bounty_event, BountyEvent},
+    payout::active_job,
+    AppState,
+};
+
+/// How often to poll the chain for new blocks
+const INDEX_INTERVAL: Duration = Duration::from_secs(15);
+/// Most blocks fetched in a single log query, to stay within rpc provider limits
+const MAX_BLOCK_RANGE: u64 = 2000;
+/// How often the balances of every live bounty are checked for deposits. Balances can't be
+/// followed through logs, since plain ETH transfers don't emit any, so this costs rpc calls per
+/// bounty and runs much less often than log indexing.
+const BALANCE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChainCursor {
+    /// Last block that was fully indexed
+    block: u64,
+}
+
+/// Periodically index new blocks of a chain
+pub async fn index_loop(state: AppState, chain: ChainConfig) {
+    let contract = chain
+        .read_only_contract()
+        .expect("Coudln't initalize contract");
+
+    let mut interval = tokio::time::interval(INDEX_INTERVAL);
+    let mut last_balance_check: Option<Instant> = None;
+    loop {
+        interval.tick().await;
+
+        let check_balances = !matches!(
+            last_balance_check,
+            Some(checked) if checked.elapsed() < BALANCE_INTERVAL
+        );
+        match index_once(&state, &chain, &contract, check_balances).await {
+            Ok(()) if check_balances => last_balance_check = Some(Instant::now()),
+            Ok(()) => {},
+            Err(err) => warn!("chain indexer failed on chain {}: {err}", chain.chain_id),
+        }
+    }
+}
+
+/// Index confirmed blocks since the cursor, and reconcile bounty balances if `check_balances`
+async fn index_once(
+    state: &AppState,
+    chain: &ChainConfig,
+    contract: &ReadOnlyContract,
+    check_balances: bool,
+) -> anyhow::Result<()> {
+    let latest = contract.client_ref().get_block_number().await?.as_u64();
+    let Some(safe_block) = latest.checked_sub(chain.confirmations) else {
+        return Ok(());
+    };
+
+    let from_block = match load_cursor(state, chain.chain_id).await {
+        Some(cursor) => cursor.block + 1,
+        None => env::var("INDEXER_START_BLOCK")
+            .ok()
+            .map(|n| {
+                n.parse::<u64>()
+                    .expect("INDEXER_START_BLOCK should be a number")
+            })
+            .unwrap_or(0),
+    };
+
+    if from_block <= safe_block {
+        let to_block = safe_block.min(from_block + MAX_BLOCK_RANGE - 1);
+        index_range(state, chain, contract, from_block, to_block).await?;
+        save_cursor(state, chain.chain_id, to_block).await;
+
+        debug!(
+            "indexed blocks {from_block} to {to_block} on chain {}",
+            chain.chain_id
+        );
+    }
+
+    if check_balances {
+        reconcile_balances(state, chain, contract, safe_block).await?;
+    }
+
+    Ok(())
+}
+
+async fn index_range(
+    state: &AppState,
+    chain: &ChainConfig,
+    contract: &ReadOnlyContract,
+    from_block: u64,
+    to_block: u64,
+) -> anyhow::Result<()> {
+    let transfers = contract
+        .transfer_filter()
+        .from_block(from_block)
+        .to_block(to_block)
+        .query_with_meta()
+        .await?;
+    for (transfer, meta) in transfers {
+        let event = ChainEvent {
+            kind: ChainEventKind::Transfer,
+            chain_id: chain.chain_id,
+            contract_address: chain.contract_address,
+            token_id: transfer.token_id.as_u64(),
+            from: transfer.from,
+            to: transfer.to,
+            amount: None,
+            block: meta.block_number.as_u64(),
+            tx_hash: Some(meta.transaction_hash),
+        };
+        record_event(
+            state,
+            &event_key(chain, meta.transaction_hash, meta.log_index),
+            &event,
+        )
+        .await;
+
+        // mints are expected, anything else on a live bounty didn't go through us
+        if !transfer.from.is_zero() {
+            reconcile_transfer(state, chain, &event).await;
+        }
+    }
+
+    let operator = contract.oracle().call().await?;
+    let approvals = contract
+        .approval_filter()
+        .from_block(from_block)
+        .to_block(to_block)
+        .query_with_meta()
+        .await?;
+    for (approval, meta) in approvals {
+        let event = ChainEvent {
+            kind: ChainEventKind::Approval,
+            chain_id: chain.chain_id,
+            contract_address: chain.contract_address,
+            token_id: approval.token_id.as_u64(),
+            from: approval.owner,
+            to: approval.approved,
+            amount: None,
+            block: meta.block_number.as_u64(),
+            tx_hash: Some(meta.transaction_hash),
+        };
+        record_event(
+            state,
+            &event_key(chain, meta.transaction_hash, meta.log_index),
+            &event,
+        )
+        .await;
+
+        if approval.approved != operator {
+            if let Some(bounty) = active_bounty(state, chain, event.token_id).await {
+                warn!(
+                    "operator approval on token {} of bounty {}/{}/{} was replaced by {:?}, payout will fail",
+                    event.token_id,
+                    bounty.issue.owner,
+                    bounty.issue.repo,
+                    bounty.issue.issue_id,
+                    approval.approved
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Close a bounty whose token was burned or moved out of its owner's wallets without going
+/// through the backend
+async fn reconcile_transfer(state: &AppState, chain: &ChainConfig, event: &ChainEvent) {
+    let Some(bounty) = active_bounty(state, chain, event.token_id).await else {
+        return;
+    };
+
+    // the payout worker moves the token before burning it
+    if let Some(bounty_id) = &bounty.id {
+        if active_job(state, bounty_id).await.is_some() {
+            return;
+        }
+    }
+
+    let owner: Option<User> = state.db_conn.select(("Users", &bounty.user)).await.unwrap();
+    if !releases_funds(event, owner.as_ref()) {
+        debug!(
+            "token {} moved between wallets of {}, bounty stays open",
+            event.token_id, bounty.user
+        );
+        return;
+    }
+
+    warn!(
+        "token {} of bounty {}/{}/{} was transferred from {:?} to {:?} outside of gitbounties",
+        event.token_id,
+        bounty.issue.owner,
+        bounty.issue.repo,
+        bounty.issue.issue_id,
+        event.from,
+        event.to
+    );
+
+    state
+        .db_conn
+        .query("UPDATE $bounty SET status = 'Closed'")
+        .bind(("bounty", &bounty.id))
+        .await
+        .unwrap();
+
+    // nothing is left to pay out to users waiting on the bounty
+    if bounty.status == BountyStatus::Claimed {
+        state
+            .db_conn
+            .query("UPDATE PendingClaims SET status = 'Refunded' WHERE bounty == $bounty AND status == 'Pending'")
+            .bind(("bounty", &bounty.id))
+            .await
+            .unwrap();
+    }
+
+    notify_bounty_event(state, &bounty, BountyEvent::Cancelled).await;
+}
+
+/// Whether a transfer takes the funds of a bounty out of its owner's hands: the token was burned,
+/// or moved to an address that isn't one of the owner's wallets
+fn releases_funds(event: &ChainEvent, owner: Option<&User>) -> bool {
+    if event.to.is_zero() {
+        return true;
+    }
+    let Some(owner) = owner else {
+        return true;
+    };
+    owner.payout_wallet() != event.to
+        && !owner
+            .active_wallets()
+            .any(|wallet| wallet.address == event.to)
+}
+
+/// Raise the reward of bounties whose token bound account received more of the reward asset
+/// since it was last looked at
+async fn reconcile_balances(
+    state: &AppState,
+    chain: &ChainConfig,
+    contract: &ReadOnlyContract,
+    safe_block: u64,
+) -> anyhow::Result<()> {
+    let mut res = state
+        .db_conn
+        .query("SELECT * FROM Bounty WHERE status INSIDE ['Open', 'Claimed'] AND chain_id == $chain_id AND contract_address == $contract_address")
+        .bind(("chain_id", chain.chain_id))
+        .bind(("contract_address", chain.contract_address))
+        .await
+        .unwrap();
+    let bounties: Vec<Bounty> = res.take(0).unwrap();
+
+    for bounty in bounties {
+        let account = contract
+            .get_account(U256::from(bounty.token_id))
+            .call()
+            .await?;
+        let balance = account_balance(
+            contract,
+            account,
+            bounty.asset.token,
+            Some(safe_block.into()),
+        )
+        .await?;
+
+        let balance = Amount(balance);
+        // bounties from before balances were tracked start from their reward. Balances going
+        // down are ignored: they are read at the safe block, which can be older than the balance
+        // recorded when the bounty was created, and funds only leave while being paid out.
+        let previous = bounty.escrow_balance.unwrap_or(bounty.reward);
+        let Some(deposit) = balance
+            .checked_sub(previous)
+            .filter(|deposit| *deposit > Amount::default())
+        else {
+            continue;
+        };
+        let reward = bounty.reward.saturating_add(deposit);
+
+        let mut res = state
+            .db_conn
+            .query("UPDATE $bounty SET reward = $reward, reward_key = $reward_key, escrow_balance = $balance RETURN AFTER")
+            .bind(("bounty", &bounty.id))
+            .bind(("reward", reward))
+            .bind(("reward_key", reward_key(bounty.asset.token, reward)))
+            .bind(("balance", balance))
+            .await
+            .unwrap();
+        let Some(updated) = res.take::<Option<Bounty>>(0).unwrap() else {
+            continue;
+        };
+
+        let event = ChainEvent {
+            kind: ChainEventKind::Deposit,
+            chain_id: chain.chain_id,
+            contract_address: chain.contract_address,
+            token_id: bounty.token_id,
+            from: Address::zero(),
+            to: account,
+            amount: Some(deposit),
+            block: safe_block,
+            tx_hash: None,
+        };
+        record_event(
+            state,
+            &format!(
+                "deposit-{}-{:?}-{}-{}",
+                chain.chain_id, chain.contract_address, bounty.token_id, safe_block
+            ),
+            &event,
+        )
+        .await;
+
+        info!(
+            "bounty {}/{}/{} topped up from {} to {}",
+            bounty.issue.owner, bounty.issue.repo, bounty.issue.issue_id, bounty.reward, reward
+        );
+
+        notify_bounty_event(state, &updated, BountyEvent::ToppedUp).await;
+    }
+
+    Ok(())
+}
+
+/// Bounty backed by a token that can still be paid out or refunded
+async fn active_bounty(state: &AppState, chain: &ChainConfig, token_id: u64) -> Option<Bounty> {
+    let mut res = state
+        .db_conn
+        .query("SELECT * FROM Bounty WHERE token_id == $token_id AND chain_id == $chain_id AND contract_address == $contract_address AND status INSIDE ['Open', 'Claimed']")
+        .bind(("token_id", token_id))
+        .bind(("chain_id", chain.chain_id))
+        .bind(("contract_address", chain.contract_address))
+        .await
+        .unwrap();
+    res.take(0).unwrap()
+}
+
+fn event_key(chain: &ChainConfig, tx_hash: H256, log_index: U256) -> String {
+    format!(
+        "{}-{:?}-{tx_hash:?}-{log_index}",
+        chain.chain_id, chain.contract_address
+    )
+}
+
+/// Store an event, keyed so that indexing the same block twice doesn't duplicate it
+async fn record_event(state: &AppState, key: &str, event: &ChainEvent) {
+    state
+        .db_conn
+        .query("UPDATE type::thing('ChainEvents', $key) CONTENT $event")
+        .bind(("key", key))
+        .bind(("event", event))
+        .await
+        .unwrap();
+}
+
+async fn load_cursor(state: &AppState, chain_id: u64) -> Option<ChainCursor> {
+    state
+        .db_conn
+        .select(("ChainCursor", chain_id as i64))
+        .await
+        .unwrap()
+}
+
+async fn save_cursor(state: &AppState, chain_id: u64, block: u64) {
+    state
+        .db_conn
+        .query("UPDATE type::thing('ChainCursor', $chain_id) CONTENT $cursor")
+        .bind(("chain_id", chain_id))
+        .bind(("cursor", ChainCursor { block }))
+        .await
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::releases_funds;
+    use crate::models::{Address, ChainEvent, ChainEventKind, User, UserWallet};
+
+    fn transfer_to(to: Address) -> ChainEvent {
+        ChainEvent {
+            kind: ChainEventKind::Transfer,
+            chain_id: 1,
+            contract_address: Address::repeat_byte(0xc0),
+            token_id: 7,
+            from: Address::repeat_byte(1),
+            to,
+            amount: None,
+            block: 100,
+            tx_hash: None,
+        }
+    }
+
+    #[test]
+    fn only_burns_and_transfers_out_of_the_owners_wallets_release_funds() {
+        let wallet = |address, removed| UserWallet {
+            address,
+            label: None,
+            chain_id: None,
+            verified: chrono::offset::Utc::now(),
+            removed,
+        };
+        let owner = User {
+            username: "alice".into(),
+            github_installations: vec![],
+            wallet_address: Address::repeat_byte(1),
+            wallets: vec![
+                wallet(Address::repeat_byte(1), None),
+                wallet(Address::repeat_byte(2), None),
+                wallet(Address::repeat_byte(3), Some(chrono::offset::Utc::now())),
+            ],
+        };
+
+        assert!(!releases_funds(
+            &transfer_to(Address::repeat_byte(2)),
+            Some(&owner)
+        ));
+        assert!(releases_funds(&transfer_to(Address::zero()), Some(&owner)));
+        // removed wallets no longer count as the owner's
+        assert!(releases_funds(
+            &transfer_to(Address::repeat_byte(3)),
+            Some(&owner)
+        ));
+        assert!(releases_funds(
+            &transfer_to(Address::repeat_byte(4)),
+            Some(&owner)
+        ));
+        assert!(releases_funds(&transfer_to(Address::repeat_byte(2)), None));
+    }
+}
@@ -23,23 +23,48 @@ use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
 
+mod amount;
 mod api;
+mod chains;
+mod claims;
 mod contract;
 mod db;
 mod ether;
+mod indexer;
 mod middleware;
 mod models;
+mod notify;
+mod payout;
 mod redis;
+mod search;
 mod session_auth;
+mod siwe;
 mod utils;
+mod wallet;
 
 #[derive(Clone)]
 pub struct AppState {
     db_conn: DBConnection,
     /// JWT token used to interact with github REST API
     github_jwt: String,
+    /// Secret github signs webhook deliveries with
+    github_webhook_secret: String,
     /// Reqwest client
     reqwest: reqwest::Client,
+    /// Full-text index over bounties
+    search: Arc<tokio::sync::RwLock<search::SearchIndex>>,
+    /// Chains bounties can be escrowed on
+    chains: Arc<chains::ChainRegistry>,
+    /// Page users that closed a bounty before registering are sent to
+    register_url: String,
+    /// Domain sign in with ethereum messages must be issued for
+    siwe_domain: String,
+    /// How long a pending claim stays open before the reward is refunded
+    claim_timeout: chrono::Duration,
+    /// Url the api is publicly served from, without a trailing slash
+    public_api_url: String,
+    /// Organizations the operator wallet funds bounties for
+    custodial: Arc<api::mint::Custodial>,
 }
 
 impl AppState {
@@ -54,17 +79,46 @@ impl AppState {
         .await
         .unwrap();
 
-        // db::migrate(&db_conn).await;
+        let chains = chains::ChainRegistry::from_env().expect("Invalid chain configuration");
+        chains
+            .validate()
+            .await
+            .expect("Failed to validate chain configuration");
+
+        db::migrate(&db_conn, &chains).await;
         // db::migrate_dummy(&db_conn).await;
 
+        let search = search::SearchIndex::build(&db_conn)
+            .await
+            .expect("Failed to build search index");
+
+        let register_url = env::var("REGISTER_URL").expect("Couldn't get REGISTER_URL env var");
+        let siwe_domain = env::var("SIWE_DOMAIN").expect("Couldn't get SIWE_DOMAIN env var");
+        let claim_timeout = claims::claim_timeout();
+        let public_api_url = env::var("PUBLIC_API_URL")
+            .expect("Couldn't get PUBLIC_API_URL env var")
+            .trim_end_matches('/')
+            .to_string();
+        let custodial = api::mint::Custodial::from_env();
+
         let reqwest = reqwest::Client::new();
         // TODO this jwt needs to be refreshed every so often
         let github_jwt = utils::generate_github_jwt();
+        let github_webhook_secret =
+            env::var("GITHUB_WEBHOOK_SECRET").expect("Couldn't get GITHUB_WEBHOOK_SECRET env var");
         debug!("github jwt {github_jwt}");
         let app_state = AppState {
             db_conn,
             github_jwt,
+            github_webhook_secret,
             reqwest,
+            search: Arc::new(tokio::sync::RwLock::new(search)),
+            chains: Arc::new(chains),
+            register_url,
+            siwe_domain,
+            claim_timeout,
+            public_api_url,
+            custodial: Arc::new(custodial),
         };
 
         app_state
@@ -115,6 +169,14 @@ async fn main() {
 
     let app_state = AppState::init().await;
 
+    tokio::spawn(claims::expire_claims_loop(app_state.clone()));
+    tokio::spawn(wallet::purge_nonces_loop(app_state.clone()));
+    tokio::spawn(siwe::purge_nonces_loop(app_state.clone()));
+    tokio::spawn(payout::payout_worker_loop(app_state.clone()));
+    for chain in app_state.chains.iter() {
+        tokio::spawn(indexer::index_loop(app_state.clone(), chain.clone()));
+    }
+
     let secret = rand::thread_rng().gen::<[u8; 64]>();
 
     let session_store = SessionMemoryStore::new();
@@ -1,8 +1,19 @@
-use gitbounties_contract::H160;
-use serde::{Deserialize, Serialize};
+use gitbounties_contract::{H160, H256};
+use serde::{Deserialize, Serialize, Serializer};
+use surrealdb::sql::Thing;
+
+pub use crate::amount::{Amount, Asset};
 
 pub type Address = H160;
 
+/// Serialize a record id as only its key, so it can be passed back in routes
+pub fn serialize_record_id<S: Serializer>(id: &Option<Thing>, s: S) -> Result<S::Ok, S::Error> {
+    match id {
+        Some(thing) => s.serialize_some(&thing.id.to_raw()),
+        None => s.serialize_none(),
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct User {
     /// Username as associated with github (could potenitally decouple from github in future)
@@ -13,11 +24,43 @@ pub struct User {
     /// List of installations the user has permission to manage
     pub github_installations: Vec<usize>,
 
-    /// Public address of the user's wallet
+    /// Public address of the wallet bounty payouts are sent to
     pub wallet_address: Address,
+
+    /// Every wallet the user has proven ownership of, including the payout wallet
+    #[serde(default)]
+    pub wallets: Vec<UserWallet>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl User {
+    /// Wallet that bounty payouts should be sent to
+    pub fn payout_wallet(&self) -> Address {
+        self.wallet_address
+    }
+
+    /// Wallets that have not been removed by the user
+    pub fn active_wallets(&self) -> impl Iterator<Item = &UserWallet> {
+        self.wallets
+            .iter()
+            .filter(|wallet| wallet.removed.is_none())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserWallet {
+    pub address: Address,
+    /// Name given by the user to tell wallets apart
+    pub label: Option<String>,
+    /// Chain the wallet is meant to be used on, if the user restricted it to one
+    pub chain_id: Option<u64>,
+    /// When ownership of the wallet was proven
+    pub verified: chrono::DateTime<chrono::offset::Utc>,
+    /// When the user removed the wallet. Removed wallets are kept for history.
+    #[serde(default)]
+    pub removed: Option<chrono::DateTime<chrono::offset::Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Issue {
     pub owner: String,
     pub repo: String,
@@ -27,16 +70,36 @@ pub struct Issue {
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum BountyStatus {
     Open,
+    /// Issue was closed by a user that hasn't registered yet, reward is held for them
+    Claimed,
     Completed,
     Closed,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Bounty {
+    /// Record id, only present when read back from the database
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_record_id"
+    )]
+    pub id: Option<Thing>,
     /// The user that owns this bounty
     pub user: String,
-    /// Compensantion of the reward
-    pub reward: u64,
+    /// Compensantion of the reward, in the smallest unit of `asset`
+    pub reward: Amount,
+    /// Asset the reward is paid in, bounties created before this was tracked are in ETH
+    #[serde(default)]
+    pub asset: Asset,
+    /// Reward with its asset in the fixed width form of `amount::reward_key`. The database filters
+    /// and sorts rewards on this, since it can't compare the decimal strings as numbers.
+    #[serde(default)]
+    pub reward_key: String,
+    /// Balance of the reward asset in the token bound account when it was last looked at, used
+    /// to detect deposits. Unknown for bounties created before this was tracked.
+    #[serde(default)]
+    pub escrow_balance: Option<Amount>,
     /// github node_id of the original issue
     pub issue: Issue,
     /// The current status of the bounty
@@ -48,4 +111,225 @@ pub struct Bounty {
     pub created: chrono::DateTime<chrono::offset::Utc>,
     /// Token ID of the bounty NFT the user has created
     pub token_id: u64,
+    /// Whether the issue lives in a private repository. Bounties created before this was tracked
+    /// are assumed private so they are never exposed publicly by accident.
+    #[serde(default = "default_private")]
+    pub private: bool,
+    /// Id of the comment the app keeps up to date on the github issue
+    #[serde(default)]
+    pub comment_id: Option<u64>,
+    /// Name of the bounty label the app applied to the github issue
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Set when the github issue was deleted, the bounty can then only be cancelled
+    #[serde(default)]
+    pub issue_deleted: bool,
+    /// Set while the app has lost access to the repository of the issue. Frozen bounties are
+    /// hidden publicly until access is restored.
+    #[serde(default)]
+    pub frozen: bool,
+    /// Chain the bounty token was minted on
+    #[serde(default)]
+    pub chain_id: u64,
+    /// Contract the bounty token was minted by
+    #[serde(default)]
+    pub contract_address: Address,
+    /// Minted and funded by the operator wallet on behalf of the organization. Refunds of
+    /// custodial bounties go back to the operator.
+    #[serde(default)]
+    pub custodial: bool,
+}
+
+fn default_private() -> bool {
+    true
+}
+
+#[cfg(test)]
+impl Bounty {
+    /// Open bounty of 1 ETH on a public repository, for tests to adjust
+    pub fn example() -> Self {
+        Bounty {
+            id: Some(Thing::from(("Bounty", "example"))),
+            user: "alice".into(),
+            reward: Amount::from(1_000_000_000_000_000_000),
+            asset: Asset::native(),
+            reward_key: crate::amount::reward_key(None, Amount::from(1_000_000_000_000_000_000)),
+            escrow_balance: None,
+            issue: Issue {
+                owner: "gitbounties".into(),
+                repo: "backend".into(),
+                issue_id: 42,
+            },
+            status: BountyStatus::Open,
+            title: "Fix the parser".into(),
+            description: "It is slow.".into(),
+            labels: vec!["bug".into()],
+            created: "2023-05-01T12:00:00Z".parse().unwrap(),
+            token_id: 7,
+            private: false,
+            comment_id: None,
+            label: None,
+            issue_deleted: false,
+            frozen: false,
+            chain_id: 1,
+            contract_address: Address::zero(),
+            custodial: false,
+        }
+    }
+}
+
+/// Record of a bounty reward that was paid out to the user that closed the issue
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Payout {
+    /// The bounty that was paid out
+    pub bounty: Thing,
+    /// Username of the user that received the reward
+    pub recipient: String,
+    /// Wallet the reward was sent to
+    pub wallet_address: Address,
+    pub reward: Amount,
+    /// Asset the reward was paid in
+    #[serde(default)]
+    pub asset: Asset,
+    pub issue: Issue,
+    /// When the bounty was created, kept to compute time to close
+    pub bounty_created: chrono::DateTime<chrono::offset::Utc>,
+    pub paid: chrono::DateTime<chrono::offset::Utc>,
+}
+
+/// Audit record of a user changing their payout wallet
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WalletChange {
+    pub username: String,
+    pub previous: Address,
+    pub new: Address,
+    pub changed: chrono::DateTime<chrono::offset::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ClaimStatus {
+    /// Waiting for the user to register
+    Pending,
+    /// User registered and was paid the reward
+    Paid,
+    /// Reward won't be paid to the user, either because they didn't register in time or because
+    /// the funds were released some other way
+    Refunded,
+}
+
+/// Reward held for a github user that closed an issue before registering
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PendingClaim {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub bounty: Thing,
+    /// Github username of the author of the pull request that closed the issue
+    pub github_login: String,
+    /// Number of the pull request that closed the issue
+    pub pull_request: u64,
+    pub created: chrono::DateTime<chrono::offset::Utc>,
+    /// When the reward is refunded if the user still hasn't registered
+    pub expires: chrono::DateTime<chrono::offset::Utc>,
+    pub status: ClaimStatus,
+}
+
+/// Installation of the github app on a user or organization account
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Installation {
+    /// Account the app is installed on
+    pub account: String,
+    /// Whether the app has access to every repository of the account
+    pub all_repositories: bool,
+    /// Names of the repositories the app was given access to
+    pub repositories: Vec<String>,
+    pub suspended: bool,
+    pub updated: chrono::DateTime<chrono::offset::Utc>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ChainEventKind {
+    /// Bounty token changed owner, minted or burned
+    Transfer,
+    /// Address approved to move the bounty token changed
+    Approval,
+    /// Funds were added to the token bound account of the bounty
+    Deposit,
+}
+
+/// Confirmed on-chain activity on a bounty token
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChainEvent {
+    pub kind: ChainEventKind,
+    #[serde(default)]
+    pub chain_id: u64,
+    /// Contract of the bounty token
+    #[serde(default)]
+    pub contract_address: Address,
+    pub token_id: u64,
+    /// Previous owner for transfers, token owner for approvals
+    pub from: Address,
+    /// New owner for transfers, approved address for approvals, token bound account for deposits
+    pub to: Address,
+    /// Amount added, only set for deposits
+    #[serde(default)]
+    pub amount: Option<Amount>,
+    pub block: u64,
+    /// Transaction that emitted the event. Deposits are detected from balances so have none.
+    #[serde(default)]
+    pub tx_hash: Option<H256>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum PayoutJobKind {
+    /// Reward goes to the user that closed the issue
+    Payout { recipient: String },
+    /// Reward goes back to the bounty owner
+    Refund,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum PayoutJobStatus {
+    /// Waiting for the next transaction to be sent
+    Queued,
+    /// A transaction was sent and is waiting to be confirmed
+    Submitted,
+    /// Funds were released and the bounty was updated
+    Confirmed,
+    /// Gave up after too many attempts, needs manual attention
+    Failed,
+}
+
+/// Persisted transfer of a bounty's escrowed funds, processed by the payout worker
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PayoutJob {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub bounty: Thing,
+    pub kind: PayoutJobKind,
+    /// Wallet the funds are released to
+    pub wallet_address: Address,
+    pub status: PayoutJobStatus,
+    /// `active` until the job fails, then its own id. A unique index on the bounty and this keeps
+    /// a single job at a time releasing the funds of a bounty.
+    #[serde(default)]
+    pub slot: String,
+    /// Transaction moving the token to the operator, so it can move ERC-20 rewards out of the
+    /// token bound account
+    #[serde(default)]
+    pub custody_tx: Option<H256>,
+    /// Transaction sending the ERC-20 reward to `wallet_address`
+    #[serde(default)]
+    pub sweep_tx: Option<H256>,
+    /// Transaction moving the token to `wallet_address`
+    #[serde(default)]
+    pub transfer_tx: Option<H256>,
+    /// Transaction burning the token, which releases the funds to its owner
+    #[serde(default)]
+    pub burn_tx: Option<H256>,
+    /// Number of failed attempts so far
+    pub attempts: u32,
+    pub next_attempt: chrono::DateTime<chrono::offset::Utc>,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    pub created: chrono::DateTime<chrono::offset::Utc>,
 }
@@ -0,0 +1,427 @@
+//! Keep github issues up to date with what happens to their bounty
+//!
+//! The app keeps a single comment on each bountied issue and edits it as the bounty changes.
+//! Comment bodies are rendered from templates, the defaults can be overridden by placing files
+//! named after the event (`created.md`, `paid.md`, ...) in `COMMENT_TEMPLATES_DIR`.
+//!
+//! Open bounties are also marked with a label on the issue, named from the `BOUNTY_LABEL`
+//! template. The label is removed once the bounty is paid out, cancelled or expires.
+
+use std::{env, fs, path::PathBuf};
+
+use log::{debug, warn};
+use reqwest::{Method, StatusCode, Url};
+use serde_json::json;
+
+use crate::{
+    api::github::{get_installation, get_installation_access_token},
+    models::Bounty,
+    AppState,
+};
+
+#[derive(Debug, Clone)]
+pub enum BountyEvent {
+    Created,
+    /// More funds were added to the bounty
+    ToppedUp,
+    /// Issue was closed by a user that hasn't registered yet
+    Claimed {
+        github_login: String,
+    },
+    Paid {
+        recipient: String,
+    },
+    Cancelled,
+    /// Claim was not settled in time and the reward was refunded
+    Expired,
+}
+
+impl BountyEvent {
+    fn template_name(&self) -> &'static str {
+        match self {
+            BountyEvent::Created => "created",
+            BountyEvent::ToppedUp => "topped_up",
+            BountyEvent::Claimed { .. } => "claimed",
+            BountyEvent::Paid { .. } => "paid",
+            BountyEvent::Cancelled => "cancelled",
+            BountyEvent::Expired => "expired",
+        }
+    }
+
+    fn default_template(&self) -> &'static str {
+        match self {
+            BountyEvent::Created => "💰 A bounty of **{reward}** has been placed on this issue. Close it with a pull request to claim the reward!",
+            BountyEvent::ToppedUp => "💰 The bounty on this issue has been increased to **{reward}**. Close it with a pull request to claim the reward!",
+            BountyEvent::Claimed { .. } => "🔒 This issue was closed by @{recipient}. The bounty of **{reward}** is held until they register at {register_url}.",
+            BountyEvent::Paid { .. } => "✅ The bounty of **{reward}** was paid to @{recipient}.",
+            BountyEvent::Cancelled => "❌ The bounty on this issue was cancelled.",
+            BountyEvent::Expired => "⌛ The bounty of **{reward}** was not claimed in time and has been refunded.",
+        }
+    }
+
+    /// Whether the bounty can still be claimed after this event
+    fn is_open(&self) -> bool {
+        matches!(
+            self,
+            BountyEvent::Created | BountyEvent::ToppedUp | BountyEvent::Claimed { .. }
+        )
+    }
+
+    fn recipient(&self) -> &str {
+        match self {
+            BountyEvent::Claimed { github_login } => github_login,
+            BountyEvent::Paid { recipient } => recipient,
+            _ => "",
+        }
+    }
+}
+
+/// Default template for the name of the bounty label
+const DEFAULT_LABEL_TEMPLATE: &str = "💰 bounty";
+/// Default color of the bounty label, as hex without the leading `#`
+const DEFAULT_LABEL_COLOR: &str = "f9d71c";
+
+/// Load the template for an event, falling back to the built in one
+fn load_template(event: &BountyEvent) -> String {
+    if let Ok(dir) = env::var("COMMENT_TEMPLATES_DIR") {
+        let path = PathBuf::from(dir).join(format!("{}.md", event.template_name()));
+        match fs::read_to_string(&path) {
+            Ok(template) => return template,
+            Err(err) => debug!("using default template, couldn't read {path:?}: {err}"),
+        }
+    }
+    event.default_template().to_string()
+}
+
+/// Fill in the placeholders of a template
+fn render(register_url: &str, template: &str, bounty: &Bounty, event: &BountyEvent) -> String {
+    template
+        .replace("{reward}", &bounty.asset.format(bounty.reward))
+        .replace("{owner}", &bounty.issue.owner)
+        .replace("{repo}", &bounty.issue.repo)
+        .replace("{issue}", &bounty.issue.issue_id.to_string())
+        .replace("{title}", &bounty.title)
+        .replace("{recipient}", event.recipient())
+        .replace("{register_url}", register_url)
+}
+
+/// Reflect a bounty event on the github issue
+pub async fn notify_bounty_event(state: &AppState, bounty: &Bounty, event: BountyEvent) {
+    let Some(installation_id) =
+        get_installation(state, &bounty.issue.owner, &bounty.issue.repo).await
+    else {
+        warn!(
+            "app is not installed on {}/{}, skipping notification",
+            bounty.issue.owner, bounty.issue.repo
+        );
+        return;
+    };
+    let installation_access_token = get_installation_access_token(state, installation_id).await;
+
+    let body = render(&state.register_url, &load_template(&event), bounty, &event);
+    upsert_bounty_comment(state, &installation_access_token, bounty, &body).await;
+
+    sync_bounty_label(state, &installation_access_token, bounty, &event).await;
+}
+
+/// Edit the bounty comment on the issue, creating it if it doesn't exist yet
+async fn upsert_bounty_comment(
+    state: &AppState,
+    installation_access_token: &str,
+    bounty: &Bounty,
+    body: &str,
+) {
+    let (owner, repo) = (&bounty.issue.owner, &bounty.issue.repo);
+
+    if let Some(comment_id) = bounty.comment_id {
+        let res = state
+            .reqwest_github(
+                Method::PATCH,
+                &format!(
+                    "https://api.github.com/repos/{owner}/{repo}/issues/comments/{comment_id}"
+                ),
+                installation_access_token,
+            )
+            .json(&json!({ "body": body }))
+            .send()
+            .await
+            .unwrap();
+
+        if res.status().is_success() {
+            return;
+        }
+        // comment may have been deleted by a maintainer, post a new one instead
+        if res.status() != StatusCode::NOT_FOUND {
+            let body = res.text().await.unwrap();
+            warn!("failed to edit bounty comment {comment_id}: {body}");
+            return;
+        }
+    }
+
+    let res = state
+        .reqwest_github(
+            Method::POST,
+            &format!(
+                "https://api.github.com/repos/{owner}/{repo}/issues/{}/comments",
+                bounty.issue.issue_id
+            ),
+            installation_access_token,
+        )
+        .json(&json!({ "body": body }))
+        .send()
+        .await
+        .unwrap();
+
+    if !res.status().is_success() {
+        let body = res.text().await.unwrap();
+        warn!(
+            "failed to comment on {owner}/{repo}#{}: {body}",
+            bounty.issue.issue_id
+        );
+        return;
+    }
+
+    let res_body = res.json::<serde_json::Value>().await.unwrap();
+    let comment_id = res_body["id"].as_u64().expect("Couldn't get comment id");
+
+    state
+        .db_conn
+        .query("UPDATE $bounty SET comment_id = $comment_id")
+        .bind(("bounty", &bounty.id))
+        .bind(("comment_id", comment_id))
+        .await
+        .unwrap();
+}
+
+/// Make sure the issue carries the right bounty label for the current state of the bounty
+async fn sync_bounty_label(
+    state: &AppState,
+    installation_access_token: &str,
+    bounty: &Bounty,
+    event: &BountyEvent,
+) {
+    let (owner, repo) = (&bounty.issue.owner, &bounty.issue.repo);
+
+    let template = env::var("BOUNTY_LABEL").unwrap_or_else(|_| DEFAULT_LABEL_TEMPLATE.to_string());
+    let desired = desired_label(&template, &state.register_url, bounty, event);
+    if bounty.label == desired {
+        return;
+    }
+
+    if let Some(old_label) = &bounty.label {
+        let res = state
+            .reqwest_github(
+                Method::DELETE,
+                label_url(
+                    &[
+                        "repos",
+                        owner,
+                        repo,
+                        "issues",
+                        &bounty.issue.issue_id.to_string(),
+                        "labels",
+                    ],
+                    old_label,
+                ),
+                installation_access_token,
+            )
+            .send()
+            .await
+            .unwrap();
+        // label may have already been removed by a maintainer
+        if !res.status().is_success() && res.status() != StatusCode::NOT_FOUND {
+            let body = res.text().await.unwrap();
+            warn!("failed to remove label '{old_label}' from {owner}/{repo}: {body}");
+        }
+    }
+
+    if let Some(new_label) = &desired {
+        ensure_label_exists(state, installation_access_token, owner, repo, new_label).await;
+
+        let res = state
+            .reqwest_github(
+                Method::POST,
+                &format!(
+                    "https://api.github.com/repos/{owner}/{repo}/issues/{}/labels",
+                    bounty.issue.issue_id
+                ),
+                installation_access_token,
+            )
+            .json(&json!({ "labels": [new_label] }))
+            .send()
+            .await
+            .unwrap();
+        if !res.status().is_success() {
+            let body = res.text().await.unwrap();
+            warn!("failed to add label '{new_label}' to {owner}/{repo}: {body}");
+            return;
+        }
+    }
+
+    state
+        .db_conn
+        .query("UPDATE $bounty SET label = $label")
+        .bind(("bounty", &bounty.id))
+        .bind(("label", &desired))
+        .await
+        .unwrap();
+}
+
+/// Label the issue should carry after `event`, none once the bounty can't be claimed anymore
+fn desired_label(
+    template: &str,
+    register_url: &str,
+    bounty: &Bounty,
+    event: &BountyEvent,
+) -> Option<String> {
+    event
+        .is_open()
+        .then(|| render(register_url, template, bounty, event))
+}
+
+/// Create the label in the repository if it doesn't exist yet
+async fn ensure_label_exists(
+    state: &AppState,
+    installation_access_token: &str,
+    owner: &str,
+    repo: &str,
+    label: &str,
+) {
+    let res = state
+        .reqwest_github(
+            Method::GET,
+            label_url(&["repos", owner, repo, "labels"], label),
+            installation_access_token,
+        )
+        .send()
+        .await
+        .unwrap();
+    if res.status() != StatusCode::NOT_FOUND {
+        return;
+    }
+
+    let color = env::var("BOUNTY_LABEL_COLOR").unwrap_or_else(|_| DEFAULT_LABEL_COLOR.to_string());
+    let res = state
+        .reqwest_github(
+            Method::POST,
+            &format!("https://api.github.com/repos/{owner}/{repo}/labels"),
+            installation_access_token,
+        )
+        .json(&json!({
+            "name": label,
+            "color": color,
+            "description": "Issue has a bounty on GitBounties",
+        }))
+        .send()
+        .await
+        .unwrap();
+    if !res.status().is_success() {
+        let body = res.text().await.unwrap();
+        warn!("failed to create label '{label}' in {owner}/{repo}: {body}");
+    }
+}
+
+/// Build a github api url ending with a label name, percent encoding the name
+fn label_url(segments: &[&str], label: &str) -> Url {
+    let mut url = Url::parse("https://api.github.com").unwrap();
+    url.path_segments_mut()
+        .unwrap()
+        .extend(segments)
+        .push(label);
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{desired_label, label_url, render, BountyEvent, DEFAULT_LABEL_TEMPLATE};
+    use crate::models::Bounty;
+
+    const REGISTER_URL: &str = "https://gitbounties.io/register";
+
+    #[test]
+    fn render_fills_in_placeholders() {
+        let bounty = Bounty::example();
+        let event = BountyEvent::Claimed {
+            github_login: "bob".into(),
+        };
+
+        assert_eq!(
+            render(
+                REGISTER_URL,
+                "{owner}/{repo}#{issue} {title}: {reward} for @{recipient}, see {register_url}",
+                &bounty,
+                &event
+            ),
+            "gitbounties/backend#42 Fix the parser: 1 ETH for @bob, see https://gitbounties.io/register"
+        );
+        // unknown placeholders are left as is
+        assert_eq!(
+            render(REGISTER_URL, "{unknown}", &bounty, &event),
+            "{unknown}"
+        );
+    }
+
+    #[test]
+    fn default_templates_have_no_unknown_placeholders() {
+        let bounty = Bounty::example();
+        let events = [
+            BountyEvent::Created,
+            BountyEvent::ToppedUp,
+            BountyEvent::Claimed {
+                github_login: "bob".into(),
+            },
+            BountyEvent::Paid {
+                recipient: "bob".into(),
+            },
+            BountyEvent::Cancelled,
+            BountyEvent::Expired,
+        ];
+        for event in events {
+            let body = render(REGISTER_URL, event.default_template(), &bounty, &event);
+            assert!(!body.contains('{'), "{body} has unfilled placeholders");
+        }
+    }
+
+    #[test]
+    fn label_is_only_kept_while_the_bounty_can_be_claimed() {
+        let bounty = Bounty::example();
+        let label =
+            |event: &BountyEvent| desired_label("💰 {reward}", REGISTER_URL, &bounty, event);
+
+        assert_eq!(label(&BountyEvent::Created).as_deref(), Some("💰 1 ETH"));
+        assert_eq!(label(&BountyEvent::ToppedUp).as_deref(), Some("💰 1 ETH"));
+        assert_eq!(
+            label(&BountyEvent::Claimed {
+                github_login: "bob".into()
+            })
+            .as_deref(),
+            Some("💰 1 ETH")
+        );
+        assert_eq!(
+            label(&BountyEvent::Paid {
+                recipient: "bob".into()
+            }),
+            None
+        );
+        assert_eq!(label(&BountyEvent::Cancelled), None);
+        assert_eq!(label(&BountyEvent::Expired), None);
+    }
+
+    #[test]
+    fn label_names_are_percent_encoded_in_urls() {
+        let url = label_url(
+            &["repos", "gitbounties", "backend", "labels"],
+            DEFAULT_LABEL_TEMPLATE,
+        );
+        assert_eq!(
+            url.as_str(),
+            "https://api.github.com/repos/gitbounties/backend/labels/%F0%9F%92%B0%20bounty"
+        );
+
+        let url = label_url(&["repos", "o", "r", "labels"], "needs/bounty");
+        assert_eq!(
+            url.as_str(),
+            "https://api.github.com/repos/o/r/labels/needs%2Fbounty"
+        );
+    }
+}
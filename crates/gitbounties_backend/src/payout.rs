@@ -0,0 +1,523 @@
+//! Moving bounty funds out of escrow, either to the user that closed the issue or back to the owner
+//!
+//! Payouts and refunds are persisted as jobs in the `PayoutJobs` table and carried out by a
+//! background worker. The token is first moved to the receiving wallet and then burned, which
+//! releases the escrowed funds to it. ERC-20 rewards are sent out of the token bound account
+//! beforehand, while the operator holds the token. Transaction hashes are stored before they are
+//! waited on and the token's on-chain owner is checked before anything is sent, so a job
+//! interrupted by a crash or a node outage resumes where it left off without sending a transfer
+//! twice.
+
+use std::time::Duration;
+
+use anyhow::anyhow;
+use gitbounties_contract::{
+    account_balance, token_owner, wait_for_receipt, ChainConfig, Middleware, Operator,
+    TypedTransaction, H256, U256,
+};
+use log::{error, info, warn};
+
+use crate::{
+    models::{
+        Address, Bounty, ChainEvent, Payout, PayoutJob, PayoutJobKind, PayoutJobStatus, User,
+    },
+    notify::{notify_bounty_event, BountyEvent},
+    AppState,
+};
+
+/// How often to look for jobs that are due
+const WORK_INTERVAL: Duration = Duration::from_secs(10);
+/// Attempts before a job is marked as failed
+const MAX_ATTEMPTS: u32 = 8;
+/// Delay before the first retry, doubled on every further attempt
+const RETRY_BASE_SECS: i64 = 30;
+/// `slot` of jobs that haven't failed
+const ACTIVE_SLOT: &str = "active";
+/// Longest delay between two attempts
+const RETRY_MAX_SECS: i64 = 60 * 60;
+/// How long to wait on a transaction before it is replaced with one paying higher fees
+const RECEIPT_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+
+/// Queue sending the reward of a bounty to `recipient`. The bounty is marked as completed once
+/// the funds are released.
+///
+/// Returns false if the funds of the bounty are already being released, or if its issue was
+/// deleted, in which case the bounty can only be refunded.
+pub async fn pay_bounty(
+    state: &AppState,
+    bounty: &Bounty,
+    recipient: &str,
+    wallet_address: &Address,
+) -> bool {
+    if bounty.issue_deleted {
+        warn!(
+            "not paying bounty {}/{}/{}, its issue was deleted",
+            bounty.issue.owner, bounty.issue.repo, bounty.issue.issue_id
+        );
+        return false;
+    }
+
+    enqueue(
+        state,
+        bounty,
+        PayoutJobKind::Payout {
+            recipient: recipient.to_string(),
+        },
+        *wallet_address,
+    )
+    .await
+}
+
+/// Queue returning the funds of a bounty to the user that created it, or to the operator for
+/// custodial bounties. The bounty is closed once the funds are released.
+///
+/// Returns false if the funds of the bounty are already being released, or if the operator of a
+/// custodial bounty's chain is no longer configured.
+pub async fn refund_bounty(state: &AppState, bounty: &Bounty) -> bool {
+    let wallet_address = if bounty.custodial {
+        let Some(operator) = state.chains.operator(bounty.chain_id) else {
+            warn!(
+                "not refunding custodial bounty {}/{}/{}, chain {} is not configured",
+                bounty.issue.owner, bounty.issue.repo, bounty.issue.issue_id, bounty.chain_id
+            );
+            return false;
+        };
+        operator.address()
+    } else {
+        let owner_data: User = state
+            .db_conn
+            .select(("Users", bounty.user.as_str()))
+            .await
+            .expect("Bounty owner should exist in database");
+        owner_data.payout_wallet()
+    };
+
+    enqueue(state, bounty, PayoutJobKind::Refund, wallet_address).await
+}
+
+async fn enqueue(
+    state: &AppState,
+    bounty: &Bounty,
+    kind: PayoutJobKind,
+    wallet_address: Address,
+) -> bool {
+    let bounty_id = bounty
+        .id
+        .clone()
+        .expect("Bounty read from database should have id");
+
+    // only one job may ever move the funds of a bounty
+    if active_job(state, &bounty_id).await.is_some() {
+        warn!(
+            "funds of bounty {}/{}/{} are already being released",
+            bounty.issue.owner, bounty.issue.repo, bounty.issue.issue_id
+        );
+        return false;
+    }
+
+    let now = chrono::offset::Utc::now();
+    let res: surrealdb::Result<PayoutJob> = state
+        .db_conn
+        .create("PayoutJobs")
+        .content(PayoutJob {
+            id: None,
+            bounty: bounty_id.clone(),
+            kind: kind.clone(),
+            wallet_address,
+            status: PayoutJobStatus::Queued,
+            slot: ACTIVE_SLOT.to_string(),
+            custody_tx: None,
+            sweep_tx: None,
+            transfer_tx: None,
+            burn_tx: None,
+            attempts: 0,
+            next_attempt: now,
+            last_error: None,
+            created: now,
+        })
+        .await;
+    match res {
+        Ok(_) => {},
+        // the unique index rejects a job queued concurrently since the check above
+        Err(_) if active_job(state, &bounty_id).await.is_some() => {
+            warn!(
+                "funds of bounty {}/{}/{} are already being released",
+                bounty.issue.owner, bounty.issue.repo, bounty.issue.issue_id
+            );
+            return false;
+        },
+        Err(err) => {
+            error!(
+                "failed to queue payout job for bounty {}/{}/{}: {err}",
+                bounty.issue.owner, bounty.issue.repo, bounty.issue.issue_id
+            );
+            return false;
+        },
+    }
+
+    info!(
+        "queued {:?} of bounty {}/{}/{} to {:?}",
+        kind, bounty.issue.owner, bounty.issue.repo, bounty.issue.issue_id, wallet_address
+    );
+
+    true
+}
+
+/// Job releasing the funds of a bounty that hasn't failed
+pub async fn active_job(state: &AppState, bounty: &surrealdb::sql::Thing) -> Option<PayoutJob> {
+    let mut res = state
+        .db_conn
+        .query("SELECT * FROM PayoutJobs WHERE bounty == $bounty AND slot == $slot")
+        .bind(("slot", ACTIVE_SLOT))
+        .bind(("bounty", bounty))
+        .await
+        .unwrap();
+    res.take(0).unwrap()
+}
+
+/// Jobs that still have to release funds to a wallet
+///
+/// The wallet is fixed when a job is queued, so these keep paying it after the user changes their
+/// payout wallet.
+pub async fn unsettled_jobs_to(state: &AppState, wallet_address: Address) -> Vec<PayoutJob> {
+    let mut res = state
+        .db_conn
+        .query("SELECT * FROM PayoutJobs WHERE wallet_address == $wallet_address AND status INSIDE ['Queued', 'Submitted']")
+        .bind(("wallet_address", wallet_address))
+        .await
+        .unwrap();
+    res.take(0).unwrap()
+}
+
+/// Periodically work through due payout jobs
+pub async fn payout_worker_loop(state: AppState) {
+    let mut interval = tokio::time::interval(WORK_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let mut res = state
+            .db_conn
+            .query("SELECT * FROM PayoutJobs WHERE status INSIDE ['Queued', 'Submitted'] AND next_attempt <= $now ORDER BY created")
+            .bind(("now", chrono::offset::Utc::now()))
+            .await
+            .unwrap();
+        let jobs: Vec<PayoutJob> = res.take(0).unwrap();
+
+        // jobs are run one at a time since they all send from the operator wallet
+        for mut job in jobs {
+            if let Err(err) = process_job(&state, &mut job).await {
+                retry_later(&state, &mut job, &err.to_string()).await;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Step {
+    /// Move the token to the operator, which can then act for its token bound account
+    Custody,
+    /// Send the ERC-20 reward out of the token bound account
+    Sweep,
+    Transfer,
+    Burn,
+}
+
+async fn process_job(state: &AppState, job: &mut PayoutJob) -> anyhow::Result<()> {
+    let bounty: Option<Bounty> = state
+        .db_conn
+        .select(("Bounty", job.bounty.id.to_raw().as_str()))
+        .await?;
+    let bounty = bounty.ok_or_else(|| anyhow!("bounty no longer exists"))?;
+
+    let (Some(chain), Some(operator)) = (
+        state.chains.for_bounty(&bounty),
+        state.chains.operator(bounty.chain_id),
+    ) else {
+        let err = format!("chain {} is not configured", bounty.chain_id);
+        fail_job(state, job, &err).await;
+        return Ok(());
+    };
+    let contract = chain.read_only_contract()?;
+    let nft = operator.contract(chain.contract_address);
+    let token_id = U256::from(bounty.token_id);
+
+    // ERC-20 rewards are sent out of the token bound account while the operator holds the token,
+    // native funds are released by burning it
+    if let Some(reward_token) = bounty.asset.token {
+        let account = contract.get_account(token_id).call().await?;
+        let balance = account_balance(&contract, account, Some(reward_token), None).await?;
+
+        if let Some(owner) = token_owner(&contract, token_id).await? {
+            if !balance.is_zero() {
+                if owner != operator.address() {
+                    let tx = nft.transfer_token(token_id, operator.address()).tx;
+                    run_step(state, job, &chain, operator, Step::Custody, tx).await?;
+                }
+
+                let tx =
+                    operator.erc20_sweep_tx(account, reward_token, job.wallet_address, balance);
+                run_step(state, job, &chain, operator, Step::Sweep, tx).await?;
+            }
+        }
+    }
+
+    // look at where the token is now to know which steps are left
+    if token_owner(&contract, token_id)
+        .await?
+        .is_some_and(|owner| owner != job.wallet_address)
+    {
+        let tx = nft.transfer_token(token_id, job.wallet_address).tx;
+        run_step(state, job, &chain, operator, Step::Transfer, tx).await?;
+    }
+    if token_owner(&contract, token_id).await?.is_some() {
+        let tx = nft.burn(token_id).tx;
+        run_step(state, job, &chain, operator, Step::Burn, tx).await?;
+    }
+
+    // the receiving wallet may have burned the token itself after an earlier run moved it there
+    let burn_tx = match job.burn_tx {
+        Some(burn_tx) => burn_tx,
+        None => {
+            let Some(burn) = indexed_burn(state, &chain, bounty.token_id).await else {
+                return Err(anyhow!(
+                    "burn of token {} is not indexed yet",
+                    bounty.token_id
+                ));
+            };
+            let Some(burn_tx) = burn.tx_hash.filter(|_| burn.from == job.wallet_address) else {
+                fail_job(state, job, "token was burned outside of gitbounties").await;
+                return Ok(());
+            };
+            record_tx(state, job, Step::Burn, burn_tx).await;
+            burn_tx
+        },
+    };
+
+    // a burn from an earlier run may not be buried yet. Once a replaced burn is mined the node
+    // no longer knows the replacement, which is fine since the token is gone either way.
+    wait_confirmed(state, job, &chain, operator, Step::Burn, burn_tx).await?;
+
+    finalize(state, job, &bounty).await;
+
+    Ok(())
+}
+
+/// Confirmed transfer burning a token, as recorded by the chain indexer
+async fn indexed_burn(state: &AppState, chain: &ChainConfig, token_id: u64) -> Option<ChainEvent> {
+    let mut res = state
+        .db_conn
+        .query("SELECT * FROM ChainEvents WHERE kind == 'Transfer' AND chain_id == $chain_id AND contract_address == $contract_address AND token_id == $token_id AND to == $zero ORDER BY block DESC LIMIT 1")
+        .bind(("chain_id", chain.chain_id))
+        .bind(("contract_address", chain.contract_address))
+        .bind(("token_id", token_id))
+        .bind(("zero", Address::zero()))
+        .await
+        .unwrap();
+    res.take(0).unwrap()
+}
+
+/// Send the transaction for a step that still has to happen, unless one sent earlier is still
+/// pending, and wait for it to be confirmed
+async fn run_step(
+    state: &AppState,
+    job: &mut PayoutJob,
+    chain: &ChainConfig,
+    operator: &Operator,
+    step: Step,
+    tx: TypedTransaction,
+) -> anyhow::Result<()> {
+    let previous = match step {
+        Step::Custody => job.custody_tx,
+        Step::Sweep => job.sweep_tx,
+        Step::Transfer => job.transfer_tx,
+        Step::Burn => job.burn_tx,
+    };
+
+    let tx_hash = match previous {
+        Some(tx_hash) if is_pending(operator.client(), tx_hash).await? => tx_hash,
+        _ => {
+            let tx_hash = operator.send(tx).await?;
+            record_tx(state, job, step, tx_hash).await;
+
+            info!("sent {step:?} of payout job {:?} in {tx_hash:?}", job.id);
+            tx_hash
+        },
+    };
+
+    if !wait_confirmed(state, job, chain, operator, step, tx_hash).await? {
+        return Err(anyhow!("transaction {tx_hash:?} was dropped"));
+    }
+
+    Ok(())
+}
+
+/// Wait for a transaction to be buried under the chain's confirmations
+///
+/// A transaction that isn't mined in time is replaced with one paying higher fees, which the next
+/// run of the job waits on. Returns false if the node doesn't know about the transaction.
+async fn wait_confirmed(
+    state: &AppState,
+    job: &mut PayoutJob,
+    chain: &ChainConfig,
+    operator: &Operator,
+    step: Step,
+    tx_hash: H256,
+) -> anyhow::Result<bool> {
+    let receipt = tokio::time::timeout(
+        RECEIPT_TIMEOUT,
+        wait_for_receipt(operator.provider(), tx_hash, chain.confirmations as usize),
+    )
+    .await;
+
+    match receipt {
+        Ok(receipt) => match receipt? {
+            None => Ok(false),
+            Some(receipt) if receipt.status != Some(1.into()) => {
+                Err(anyhow!("transaction {tx_hash:?} reverted"))
+            },
+            Some(_) => Ok(true),
+        },
+        Err(_) => {
+            if let Some(replacement) = operator.escalate(tx_hash).await? {
+                record_tx(state, job, step, replacement).await;
+                info!("replaced stuck {step:?} {tx_hash:?} with {replacement:?}");
+            }
+            Err(anyhow!("transaction {tx_hash:?} is not confirmed yet"))
+        },
+    }
+}
+
+/// Persist the hash of a transaction before waiting on it, so a restart picks it back up
+async fn record_tx(state: &AppState, job: &mut PayoutJob, step: Step, tx_hash: H256) {
+    match step {
+        Step::Custody => job.custody_tx = Some(tx_hash),
+        Step::Sweep => job.sweep_tx = Some(tx_hash),
+        Step::Transfer => job.transfer_tx = Some(tx_hash),
+        Step::Burn => job.burn_tx = Some(tx_hash),
+    }
+    job.status = PayoutJobStatus::Submitted;
+    save_job(state, job).await;
+}
+
+/// Whether a transaction sent earlier is still waiting to be mined, in which case it must not be
+/// sent again
+async fn is_pending<M: Middleware>(client: &M, tx_hash: H256) -> anyhow::Result<bool>
+where
+    M::Error: 'static,
+{
+    let tx = client.get_transaction(tx_hash).await?;
+    Ok(tx.is_some_and(|tx| tx.block_number.is_none()))
+}
+
+/// Record the outcome of a job whose funds were released
+async fn finalize(state: &AppState, job: &mut PayoutJob, bounty: &Bounty) {
+    match &job.kind {
+        PayoutJobKind::Payout { recipient } => {
+            state
+                .db_conn
+                .query("UPDATE $bounty SET status = 'Completed'")
+                .bind(("bounty", &job.bounty))
+                .await
+                .unwrap();
+
+            // keep a record of the payout for statistics
+            let _res: Payout = state
+                .db_conn
+                .create("Payouts")
+                .content(Payout {
+                    bounty: job.bounty.clone(),
+                    recipient: recipient.clone(),
+                    wallet_address: job.wallet_address,
+                    reward: bounty.reward,
+                    asset: bounty.asset.clone(),
+                    issue: bounty.issue.clone(),
+                    bounty_created: bounty.created,
+                    paid: chrono::offset::Utc::now(),
+                })
+                .await
+                .unwrap();
+
+            info!(
+                "paid bounty {}/{}/{} to {}",
+                bounty.issue.owner, bounty.issue.repo, bounty.issue.issue_id, recipient
+            );
+
+            notify_bounty_event(
+                state,
+                bounty,
+                BountyEvent::Paid {
+                    recipient: recipient.clone(),
+                },
+            )
+            .await;
+        },
+        PayoutJobKind::Refund => {
+            state
+                .db_conn
+                .query("UPDATE $bounty SET status = 'Closed'")
+                .bind(("bounty", &job.bounty))
+                .await
+                .unwrap();
+
+            info!(
+                "refunded bounty {}/{}/{} to {}",
+                bounty.issue.owner, bounty.issue.repo, bounty.issue.issue_id, bounty.user
+            );
+        },
+    }
+
+    job.status = PayoutJobStatus::Confirmed;
+    save_job(state, job).await;
+}
+
+/// Schedule another attempt with exponential backoff, giving up after `MAX_ATTEMPTS`
+async fn retry_later(state: &AppState, job: &mut PayoutJob, err: &str) {
+    job.attempts += 1;
+    if job.attempts >= MAX_ATTEMPTS {
+        fail_job(state, job, err).await;
+        return;
+    }
+
+    let delay = (RETRY_BASE_SECS << (job.attempts - 1)).min(RETRY_MAX_SECS);
+    job.next_attempt = chrono::offset::Utc::now() + chrono::Duration::seconds(delay);
+    job.last_error = Some(err.to_string());
+    save_job(state, job).await;
+
+    warn!(
+        "payout job {:?} failed (attempt {}), retrying in {}s: {}",
+        job.id, job.attempts, delay, err
+    );
+}
+
+async fn fail_job(state: &AppState, job: &mut PayoutJob, err: &str) {
+    job.status = PayoutJobStatus::Failed;
+    job.last_error = Some(err.to_string());
+    save_job(state, job).await;
+
+    // free the bounty for another job
+    state
+        .db_conn
+        .query("UPDATE $job SET slot = <string> id")
+        .bind(("job", &job.id))
+        .await
+        .unwrap();
+
+    error!("payout job {:?} failed: {}", job.id, err);
+}
+
+async fn save_job(state: &AppState, job: &PayoutJob) {
+    state
+        .db_conn
+        .query("UPDATE $job SET status = $status, custody_tx = $custody_tx, sweep_tx = $sweep_tx, transfer_tx = $transfer_tx, burn_tx = $burn_tx, attempts = $attempts, next_attempt = $next_attempt, last_error = $last_error")
+        .bind(("job", &job.id))
+        .bind(("status", job.status))
+        .bind(("custody_tx", job.custody_tx))
+        .bind(("sweep_tx", job.sweep_tx))
+        .bind(("transfer_tx", job.transfer_tx))
+        .bind(("burn_tx", job.burn_tx))
+        .bind(("attempts", job.attempts))
+        .bind(("next_attempt", job.next_attempt))
+        .bind(("last_error", &job.last_error))
+        .await
+        .unwrap();
+}
@@ -0,0 +1,420 @@
+//! Small in-process full-text index over bounty titles, descriptions and labels
+//!
+//! The version of surrealdb we are on has no full-text indexes, so we keep a tiny inverted index
+//! in memory. It is rebuilt from the database on startup and kept up to date as bounties change.
+
+use std::collections::{HashMap, HashSet};
+
+use log::info;
+use serde::Serialize;
+
+use crate::{db::DBConnection, models::Bounty};
+
+/// How much a match in each field counts towards the score
+const TITLE_WEIGHT: f32 = 3.0;
+const LABEL_WEIGHT: f32 = 2.0;
+const DESCRIPTION_WEIGHT: f32 = 1.0;
+
+/// Number of characters of context to keep on either side of a highlighted match
+const SNIPPET_CONTEXT: usize = 60;
+
+const STOPWORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "by", "for", "from", "in", "is", "it", "of", "on",
+    "or", "that", "the", "this", "to", "with",
+];
+
+#[derive(Debug, Default)]
+struct Document {
+    /// User that owns the bounty
+    owner: String,
+    /// Bounty is on a private repository, only its owner may find it
+    private: bool,
+    title: String,
+    description: String,
+    labels: Vec<String>,
+    /// Weighted number of terms in the document, used for length normalization
+    length: f32,
+}
+
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    docs: HashMap<String, Document>,
+    /// term -> (document id -> weighted term frequency)
+    postings: HashMap<String, HashMap<String, f32>>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Highlights {
+    pub title: String,
+    /// Snippet of the description around the first match, if the description matched
+    pub description: Option<String>,
+    /// Labels that matched the query
+    pub labels: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct SearchHit {
+    pub id: String,
+    pub score: f32,
+    pub highlights: Highlights,
+}
+
+impl SearchIndex {
+    /// Build the index from every bounty in the database
+    pub async fn build(db_conn: &DBConnection) -> surrealdb::Result<Self> {
+        let mut res = db_conn.query("SELECT * FROM Bounty").await?;
+        let bounties: Vec<Bounty> = res.take(0)?;
+
+        let mut index = SearchIndex::default();
+        for bounty in bounties.iter() {
+            index.insert_bounty(bounty);
+        }
+
+        info!("Indexed {} bounties for search", index.docs.len());
+
+        Ok(index)
+    }
+
+    /// Add or replace a bounty in the index. Bounties whose issue was deleted are left out.
+    pub fn insert_bounty(&mut self, bounty: &Bounty) {
+        let Some(id) = &bounty.id else {
+            return;
+        };
+        if bounty.issue_deleted {
+            self.remove(&id.id.to_raw());
+            return;
+        }
+        self.insert(
+            &id.id.to_raw(),
+            &bounty.user,
+            bounty.private,
+            &bounty.title,
+            &bounty.description,
+            &bounty.labels,
+        );
+    }
+
+    pub fn insert(
+        &mut self,
+        id: &str,
+        owner: &str,
+        private: bool,
+        title: &str,
+        description: &str,
+        labels: &[String],
+    ) {
+        self.remove(id);
+
+        let mut frequencies: HashMap<String, f32> = HashMap::new();
+        let mut length = 0.0;
+        let fields = [(title, TITLE_WEIGHT), (description, DESCRIPTION_WEIGHT)]
+            .into_iter()
+            .chain(labels.iter().map(|label| (label.as_str(), LABEL_WEIGHT)));
+        for (text, weight) in fields {
+            for term in analyze(text) {
+                *frequencies.entry(term).or_default() += weight;
+                length += weight;
+            }
+        }
+
+        for (term, frequency) in frequencies {
+            self.postings
+                .entry(term)
+                .or_default()
+                .insert(id.to_string(), frequency);
+        }
+
+        self.docs.insert(
+            id.to_string(),
+            Document {
+                owner: owner.to_string(),
+                private,
+                title: title.to_string(),
+                description: description.to_string(),
+                labels: labels.to_vec(),
+                length,
+            },
+        );
+    }
+
+    pub fn remove(&mut self, id: &str) {
+        if self.docs.remove(id).is_none() {
+            return;
+        }
+        self.postings.retain(|_, docs| {
+            docs.remove(id);
+            !docs.is_empty()
+        });
+    }
+
+    /// Rank documents visible to `viewer` against a query, best match first
+    ///
+    /// Bounties on private repositories are only visible to the user that owns them.
+    pub fn search(&self, query: &str, viewer: &str, limit: usize) -> Vec<SearchHit> {
+        let terms: HashSet<String> = analyze(query).collect();
+        if terms.is_empty() {
+            return vec![];
+        }
+
+        let doc_count = self.docs.len() as f32;
+        let mut scores: HashMap<&str, f32> = HashMap::new();
+        for term in terms.iter() {
+            let Some(docs) = self.postings.get(term) else {
+                continue;
+            };
+            // smoothed inverse document frequency, rare terms are worth more
+            let idf =
+                (1.0 + (doc_count - docs.len() as f32 + 0.5) / (docs.len() as f32 + 0.5)).ln();
+            for (id, frequency) in docs.iter() {
+                let doc = &self.docs[id];
+                if doc.private && doc.owner != viewer {
+                    continue;
+                }
+                let length = doc.length.max(1.0);
+                *scores.entry(id.as_str()).or_default() += idf * frequency / length.sqrt();
+            }
+        }
+
+        let mut ranked = scores.into_iter().collect::<Vec<_>>();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        ranked.truncate(limit);
+
+        ranked
+            .into_iter()
+            .map(|(id, score)| {
+                let doc = &self.docs[id];
+                SearchHit {
+                    id: id.to_string(),
+                    score,
+                    highlights: Highlights {
+                        title: highlight(&doc.title, &terms, None)
+                            .unwrap_or_else(|| escape_html(&doc.title)),
+                        description: highlight(&doc.description, &terms, Some(SNIPPET_CONTEXT)),
+                        labels: doc
+                            .labels
+                            .iter()
+                            .filter(|label| analyze(label).any(|term| terms.contains(&term)))
+                            .cloned()
+                            .collect(),
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+/// Split text into normalized search terms
+fn analyze(text: &str) -> impl Iterator<Item = String> + '_ {
+    tokenize(text).filter_map(|(_, token)| normalize(token))
+}
+
+/// Split text into words along with their byte offset
+fn tokenize(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(move |token| (token.as_ptr() as usize - text.as_ptr() as usize, token))
+}
+
+/// Lowercase, drop stopwords and strip common english suffixes
+fn normalize(token: &str) -> Option<String> {
+    let token = token.to_lowercase();
+    if STOPWORDS.contains(&token.as_str()) {
+        return None;
+    }
+    Some(stem(&token).to_string())
+}
+
+/// Strip an inflection and then a trailing `e`, so that e.g. `parse`, `parses`, `parsed` and
+/// `parsing` all share the stem `pars`. Stems are kept at least three characters long.
+fn stem(token: &str) -> &str {
+    let long_enough = |stem: &str| stem.chars().count() >= 3;
+
+    let mut stem = token;
+    for suffix in ["ing", "ed", "s"] {
+        if let Some(stripped) = stem.strip_suffix(suffix) {
+            // `class` is not a plural
+            if long_enough(stripped) && !(suffix == "s" && stripped.ends_with('s')) {
+                stem = stripped;
+                break;
+            }
+        }
+    }
+    match stem.strip_suffix('e') {
+        Some(stripped) if long_enough(stripped) => stripped,
+        _ => stem,
+    }
+}
+
+/// Wrap matching words in `<mark>` tags.
+///
+/// If `context` is set, only a window of text around the first match is returned. Returns `None`
+/// if nothing in the text matched.
+fn highlight(text: &str, terms: &HashSet<String>, context: Option<usize>) -> Option<String> {
+    let matches = tokenize(text)
+        .filter(|(_, token)| normalize(token).is_some_and(|term| terms.contains(&term)))
+        .map(|(offset, token)| (offset, offset + token.len()))
+        .collect::<Vec<_>>();
+    let (first_start, _) = *matches.first()?;
+
+    let (start, end) = match context {
+        Some(context) => (
+            floor_char_boundary(text, first_start.saturating_sub(context)),
+            floor_char_boundary(text, (first_start + context).min(text.len())),
+        ),
+        None => (0, text.len()),
+    };
+
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    let mut cursor = start;
+    for (match_start, match_end) in matches {
+        if match_start < cursor || match_end > end {
+            continue;
+        }
+        snippet.push_str(&escape_html(&text[cursor..match_start]));
+        snippet.push_str("<mark>");
+        snippet.push_str(&escape_html(&text[match_start..match_end]));
+        snippet.push_str("</mark>");
+        cursor = match_end;
+    }
+    snippet.push_str(&escape_html(&text[cursor..end]));
+    if end < text.len() {
+        snippet.push('…');
+    }
+
+    Some(snippet)
+}
+
+fn floor_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize, SearchIndex};
+
+    fn index() -> SearchIndex {
+        let mut index = SearchIndex::default();
+        index.insert(
+            "a",
+            "alice",
+            false,
+            "Rewrite the parser in Rust",
+            "The current parser is slow and allocates a lot.",
+            &["rust".into(), "performance".into()],
+        );
+        index.insert(
+            "b",
+            "bob",
+            false,
+            "Fix typo in README",
+            "Mentions rust once but is about docs.",
+            &["docs".into()],
+        );
+        index.insert(
+            "c",
+            "alice",
+            false,
+            "Add dark mode",
+            "Users want a dark theme.",
+            &["frontend".into()],
+        );
+        index
+    }
+
+    #[test]
+    fn ranks_title_matches_first() {
+        let hits = index().search("rust parser", "carol", 10);
+
+        assert_eq!(
+            hits.iter().map(|hit| hit.id.as_str()).collect::<Vec<_>>(),
+            vec!["a", "b"]
+        );
+        assert_eq!(
+            hits[0].highlights.title,
+            "Rewrite the <mark>parser</mark> in <mark>Rust</mark>"
+        );
+        assert_eq!(hits[0].highlights.labels, vec!["rust".to_string()]);
+    }
+
+    #[test]
+    fn stems_and_ignores_case() {
+        let hits = index().search("Parsers", "carol", 10);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].id, "a");
+    }
+
+    #[test]
+    fn inflections_share_a_stem() {
+        for words in [
+            ["parse", "parses", "parsed", "parsing"],
+            ["fix", "fixes", "fixed", "fixing"],
+            ["issue", "issues", "issued", "issuing"],
+        ] {
+            let stems = words.map(|word| normalize(word).unwrap());
+            assert!(
+                stems.iter().all(|stem| *stem == stems[0]),
+                "{words:?} stemmed to {stems:?}"
+            );
+        }
+        assert_eq!(normalize("class"), normalize("classes"));
+        // too short to strip anything
+        assert_eq!(normalize("use").as_deref(), Some("use"));
+        assert_eq!(normalize("The"), None);
+    }
+
+    #[test]
+    fn private_bounties_are_only_found_by_their_owner() {
+        let mut index = index();
+        index.insert(
+            "d",
+            "alice",
+            true,
+            "Secret parser",
+            "On a private repository.",
+            &[],
+        );
+
+        let ids = |viewer| {
+            index
+                .search("parser", viewer, 10)
+                .into_iter()
+                .map(|hit| hit.id)
+                .collect::<Vec<_>>()
+        };
+        assert!(ids("alice").contains(&"d".to_string()));
+        assert!(!ids("bob").contains(&"d".to_string()));
+    }
+
+    #[test]
+    fn removed_documents_are_not_returned() {
+        let mut index = index();
+        index.remove("a");
+
+        let hits = index.search("parser", "carol", 10);
+        assert!(hits.is_empty());
+    }
+
+    #[test]
+    fn description_snippet_is_escaped() {
+        let mut index = SearchIndex::default();
+        index.insert("a", "alice", false, "Title", "use <script> to parse", &[]);
+
+        let hits = index.search("parse", "carol", 10);
+        assert_eq!(
+            hits[0].highlights.description.as_deref(),
+            Some("use &lt;script&gt; to <mark>parse</mark>")
+        );
+    }
+}
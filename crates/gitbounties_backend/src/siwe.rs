@@ -0,0 +1,287 @@
+//! Sign-In With Ethereum (EIP-4361)
+//!
+//! Parses SIWE messages and verifies them against a server issued nonce.
+
+use std::{str::FromStr, time::Duration};
+
+use anyhow::{anyhow, bail};
+use gitbounties_contract::{parse_address, Signature};
+use log::{debug, warn};
+use rand::{distributions::Alphanumeric, Rng};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{models::Address, AppState};
+
+/// How long a nonce can be used after it was issued, in minutes
+const NONCE_TTL_MINUTES: i64 = 10;
+const NONCE_LENGTH: usize = 17;
+/// How often nonces that were never used are deleted
+const PURGE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+const HEADER_SUFFIX: &str = " wants you to sign in with your Ethereum account:";
+
+type DateTime = chrono::DateTime<chrono::offset::Utc>;
+
+#[derive(Debug, PartialEq)]
+pub struct SiweMessage {
+    pub domain: String,
+    pub address: Address,
+    pub statement: Option<String>,
+    pub uri: String,
+    pub version: String,
+    pub chain_id: u64,
+    pub nonce: String,
+    pub issued_at: DateTime,
+    pub expiration_time: Option<DateTime>,
+    pub not_before: Option<DateTime>,
+    pub request_id: Option<String>,
+    pub resources: Vec<String>,
+}
+
+fn parse_time(value: &str) -> anyhow::Result<DateTime> {
+    Ok(chrono::DateTime::parse_from_rfc3339(value)?.with_timezone(&chrono::offset::Utc))
+}
+
+impl FromStr for SiweMessage {
+    type Err = anyhow::Error;
+
+    fn from_str(message: &str) -> anyhow::Result<Self> {
+        let mut lines = message.lines();
+
+        let header = lines.next().ok_or_else(|| anyhow!("empty message"))?;
+        let domain = header
+            .strip_suffix(HEADER_SUFFIX)
+            .ok_or_else(|| anyhow!("missing header"))?;
+        // domain may optionally be prefixed with a scheme
+        let domain = domain.split_once("://").map_or(domain, |(_, rest)| rest);
+
+        let address = lines.next().ok_or_else(|| anyhow!("missing address"))?;
+        let address = parse_address(address)?;
+
+        let mut statement = None;
+        let mut uri = None;
+        let mut version = None;
+        let mut chain_id = None;
+        let mut nonce = None;
+        let mut issued_at = None;
+        let mut expiration_time = None;
+        let mut not_before = None;
+        let mut request_id = None;
+        let mut resources = vec![];
+        let mut in_resources = false;
+
+        for line in lines {
+            if in_resources {
+                let resource = line
+                    .strip_prefix("- ")
+                    .ok_or_else(|| anyhow!("malformed resource"))?;
+                resources.push(resource.to_string());
+                continue;
+            }
+            if line.is_empty() {
+                continue;
+            }
+            if line == "Resources:" {
+                in_resources = true;
+                continue;
+            }
+
+            match line.split_once(": ") {
+                Some(("URI", value)) => uri = Some(value.to_string()),
+                Some(("Version", value)) => version = Some(value.to_string()),
+                Some(("Chain ID", value)) => chain_id = Some(value.parse::<u64>()?),
+                Some(("Nonce", value)) => nonce = Some(value.to_string()),
+                Some(("Issued At", value)) => issued_at = Some(parse_time(value)?),
+                Some(("Expiration Time", value)) => expiration_time = Some(parse_time(value)?),
+                Some(("Not Before", value)) => not_before = Some(parse_time(value)?),
+                Some(("Request ID", value)) => request_id = Some(value.to_string()),
+                // the statement is the only free form line, and comes before all the fields
+                _ if uri.is_none() && statement.is_none() => statement = Some(line.to_string()),
+                _ => bail!("unexpected line '{line}'"),
+            }
+        }
+
+        let version = version.ok_or_else(|| anyhow!("missing version"))?;
+        if version != "1" {
+            bail!("unsupported version {version}");
+        }
+
+        Ok(SiweMessage {
+            domain: domain.to_string(),
+            address,
+            statement,
+            uri: uri.ok_or_else(|| anyhow!("missing uri"))?,
+            version,
+            chain_id: chain_id.ok_or_else(|| anyhow!("missing chain id"))?,
+            nonce: nonce.ok_or_else(|| anyhow!("missing nonce"))?,
+            issued_at: issued_at.ok_or_else(|| anyhow!("missing issued at"))?,
+            expiration_time,
+            not_before,
+            request_id,
+            resources,
+        })
+    }
+}
+
+impl SiweMessage {
+    /// Check that the message is within its validity period at `now`
+    pub fn valid_at(&self, now: DateTime) -> bool {
+        let expired = matches!(self.expiration_time, Some(expiry) if now >= expiry);
+        let premature = matches!(self.not_before, Some(not_before) if now < not_before);
+        !expired && !premature
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SiweNonce {
+    pub created: DateTime,
+}
+
+/// Generate and store a new nonce to be embedded in a SIWE message
+pub async fn issue_nonce(state: &AppState) -> String {
+    let nonce: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(NONCE_LENGTH)
+        .map(char::from)
+        .collect();
+
+    let _res: SiweNonce = state
+        .db_conn
+        .create(("SiweNonces", nonce.as_str()))
+        .content(SiweNonce {
+            created: chrono::offset::Utc::now(),
+        })
+        .await
+        .unwrap();
+
+    nonce
+}
+
+/// Periodically delete nonces that expired without being used
+pub async fn purge_nonces_loop(state: AppState) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        state
+            .db_conn
+            .query("DELETE SiweNonces WHERE created < $cutoff")
+            .bind((
+                "cutoff",
+                chrono::offset::Utc::now() - chrono::Duration::minutes(NONCE_TTL_MINUTES),
+            ))
+            .await
+            .unwrap();
+    }
+}
+
+/// Verify a signed SIWE message, returning the address that signed it
+pub async fn verify(
+    state: &AppState,
+    message: &str,
+    signature: &str,
+) -> Result<Address, (StatusCode, &'static str)> {
+    let parsed = match SiweMessage::from_str(message) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            warn!("failed to parse siwe message: {err}");
+            return Err((StatusCode::BAD_REQUEST, "malformed message"));
+        },
+    };
+
+    if parsed.domain != state.siwe_domain {
+        return Err((StatusCode::BAD_REQUEST, "domain mismatch"));
+    }
+
+    let now = chrono::offset::Utc::now();
+    if !parsed.valid_at(now) {
+        return Err((StatusCode::BAD_REQUEST, "message expired"));
+    }
+
+    // nonces are single use, consume it in the same statement that reads it so concurrent requests
+    // can't both use it
+    let mut res = state
+        .db_conn
+        .query("DELETE type::thing('SiweNonces', $nonce) RETURN BEFORE")
+        .bind(("nonce", &parsed.nonce))
+        .await
+        .unwrap();
+    let stored: Option<SiweNonce> = res.take(0).unwrap();
+    let Some(stored) = stored else {
+        return Err((StatusCode::BAD_REQUEST, "invalid nonce"));
+    };
+    if now - stored.created > chrono::Duration::minutes(NONCE_TTL_MINUTES) {
+        return Err((StatusCode::BAD_REQUEST, "nonce expired"));
+    }
+
+    let Ok(signature) = Signature::from_str(signature) else {
+        return Err((StatusCode::BAD_REQUEST, "malformed signature"));
+    };
+    let Ok(signer) = signature.recover(message) else {
+        return Err((StatusCode::BAD_REQUEST, "invalid signature"));
+    };
+    if signer != parsed.address {
+        return Err((StatusCode::FORBIDDEN, "signature does not match address"));
+    }
+
+    debug!("verified siwe message for {signer:?}");
+
+    Ok(signer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::{parse_time, SiweMessage};
+
+    const MESSAGE: &str = "gitbounties.io wants you to sign in with your Ethereum account:
+0x70997970C51812dc3A010C7d01b50e0d17dc79C8
+
+Sign in to GitBounties
+
+URI: https://gitbounties.io/login
+Version: 1
+Chain ID: 1
+Nonce: 32891756
+Issued At: 2021-09-30T16:25:24Z
+Expiration Time: 2021-09-30T17:25:24Z
+Resources:
+- https://gitbounties.io/terms";
+
+    #[test]
+    fn parse_message() {
+        let message = SiweMessage::from_str(MESSAGE).unwrap();
+
+        assert_eq!(message.domain, "gitbounties.io");
+        assert_eq!(
+            message.address,
+            "0x70997970C51812dc3A010C7d01b50e0d17dc79C8"
+                .parse()
+                .unwrap()
+        );
+        assert_eq!(message.statement.as_deref(), Some("Sign in to GitBounties"));
+        assert_eq!(message.uri, "https://gitbounties.io/login");
+        assert_eq!(message.chain_id, 1);
+        assert_eq!(message.nonce, "32891756");
+        assert_eq!(message.resources, vec!["https://gitbounties.io/terms"]);
+
+        assert!(message.valid_at(parse_time("2021-09-30T17:00:00Z").unwrap()));
+        assert!(!message.valid_at(parse_time("2021-09-30T18:00:00Z").unwrap()));
+    }
+
+    #[test]
+    fn parse_message_without_statement() {
+        let message = MESSAGE.replace("Sign in to GitBounties\n\n", "");
+        let message = SiweMessage::from_str(&message).unwrap();
+
+        assert_eq!(message.statement, None);
+        assert_eq!(message.nonce, "32891756");
+    }
+
+    #[test]
+    fn reject_missing_fields() {
+        let message = MESSAGE.replace("Nonce: 32891756\n", "");
+        assert!(SiweMessage::from_str(&message).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+//! Proving ownership of a wallet
+//!
+//! The server issues a one time nonce for a given address, the user signs a message containing it
+//! with `personal_sign` (EIP-191) and we recover the signer from the signature.
+
+use std::{str::FromStr, time::Duration};
+
+use gitbounties_contract::{to_checksum, Signature};
+use log::{debug, warn};
+use rand::{distributions::Alphanumeric, Rng};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{models::Address, AppState};
+
+/// How long a nonce can be used after it was issued, in minutes
+const NONCE_TTL_MINUTES: i64 = 10;
+const NONCE_LENGTH: usize = 32;
+/// How often nonces that were never used are deleted
+const PURGE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WalletNonce {
+    /// Address the nonce was issued for
+    pub address: Address,
+    pub created: chrono::DateTime<chrono::offset::Utc>,
+}
+
+/// Message the user is expected to sign to prove they own `address`
+pub fn ownership_message(address: &Address, nonce: &str) -> String {
+    format!(
+        "Sign this message to prove you own the wallet {} on GitBounties.\n\nNonce: {}",
+        to_checksum(address, None),
+        nonce
+    )
+}
+
+/// Generate and store a new nonce for `address`
+pub async fn issue_nonce(state: &AppState, address: &Address) -> String {
+    let nonce: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(NONCE_LENGTH)
+        .map(char::from)
+        .collect();
+
+    let _res: WalletNonce = state
+        .db_conn
+        .create(("WalletNonces", nonce.as_str()))
+        .content(WalletNonce {
+            address: *address,
+            created: chrono::offset::Utc::now(),
+        })
+        .await
+        .unwrap();
+
+    nonce
+}
+
+/// Check that `signature` was produced by `address` signing the ownership message for `nonce`.
+///
+/// The nonce is consumed whether or not verification succeeds.
+pub async fn verify_ownership(
+    state: &AppState,
+    address: &Address,
+    nonce: &str,
+    signature: &str,
+) -> Result<(), (StatusCode, &'static str)> {
+    // consume the nonce in the same statement that reads it, so concurrent requests can't both use
+    // it
+    let mut res = state
+        .db_conn
+        .query("DELETE type::thing('WalletNonces', $nonce) RETURN BEFORE")
+        .bind(("nonce", nonce))
+        .await
+        .unwrap();
+    let stored: Option<WalletNonce> = res.take(0).unwrap();
+
+    let Some(stored) = stored else {
+        return Err((StatusCode::BAD_REQUEST, "invalid nonce"));
+    };
+    if stored.address != *address {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "nonce was issued for another wallet",
+        ));
+    }
+    if chrono::offset::Utc::now() - stored.created > chrono::Duration::minutes(NONCE_TTL_MINUTES) {
+        return Err((StatusCode::BAD_REQUEST, "nonce expired"));
+    }
+
+    check_signature(address, nonce, signature)
+}
+
+/// Periodically delete nonces that expired without being used
+pub async fn purge_nonces_loop(state: AppState) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        state
+            .db_conn
+            .query("DELETE WalletNonces WHERE created < $cutoff")
+            .bind((
+                "cutoff",
+                chrono::offset::Utc::now() - chrono::Duration::minutes(NONCE_TTL_MINUTES),
+            ))
+            .await
+            .unwrap();
+    }
+}
+
+/// Check that `signature` is `address` signing the ownership message for `nonce`
+fn check_signature(
+    address: &Address,
+    nonce: &str,
+    signature: &str,
+) -> Result<(), (StatusCode, &'static str)> {
+    let Ok(signature) = Signature::from_str(signature) else {
+        return Err((StatusCode::BAD_REQUEST, "malformed signature"));
+    };
+    let message = ownership_message(address, nonce);
+    let Ok(signer) = signature.recover(message.as_str()) else {
+        return Err((StatusCode::BAD_REQUEST, "invalid signature"));
+    };
+
+    if signer != *address {
+        warn!("wallet ownership signed by {signer:?} instead of {address:?}");
+        return Err((StatusCode::FORBIDDEN, "signature does not match wallet"));
+    }
+
+    debug!("verified ownership of wallet {address:?}");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use gitbounties_contract::{LocalWallet, Signer};
+    use reqwest::StatusCode;
+
+    use super::{check_signature, ownership_message};
+
+    /// First development account of hardhat and anvil
+    const KEY: &str = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+
+    #[tokio::test]
+    async fn accepts_only_the_wallet_signing_its_message() {
+        let wallet: LocalWallet = KEY.parse().unwrap();
+        let address = wallet.address();
+        let signature = wallet
+            .sign_message(ownership_message(&address, "nonce"))
+            .await
+            .unwrap()
+            .to_string();
+
+        assert!(check_signature(&address, "nonce", &signature).is_ok());
+
+        // same signature over another nonce recovers to some other address
+        assert_eq!(
+            check_signature(&address, "other", &signature)
+                .unwrap_err()
+                .0,
+            StatusCode::FORBIDDEN
+        );
+        // a proof for this wallet doesn't prove ownership of another one
+        let other = LocalWallet::new(&mut rand::thread_rng()).address();
+        assert_eq!(
+            check_signature(&other, "nonce", &signature).unwrap_err().0,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            check_signature(&address, "nonce", "0xdead").unwrap_err().0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
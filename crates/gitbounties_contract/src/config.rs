@@ -0,0 +1,326 @@
+//! Per deployment chain settings
+
+use std::env;
+
+use anyhow::{anyhow, Context};
+use ethers::{
+    providers::{Http, Middleware, Provider, Ws},
+    types::{transaction::eip2718::TypedTransaction, Address, Chain, U256},
+    utils::parse_units,
+};
+use reqwest::Url;
+
+use crate::{get_read_only_contract, ReadOnlyContract};
+
+/// Smallest fee increase, in percent, nodes accept for replacing a pending transaction
+const MIN_REPLACEMENT_BUMP: u64 = 10;
+
+/// Limits on the fees paid by transactions sent by the operator
+#[derive(Debug, Clone, Default)]
+pub struct GasPolicy {
+    /// Highest total fee per gas the operator is willing to pay
+    pub max_fee_per_gas: Option<U256>,
+    /// Highest tip per gas the operator is willing to pay
+    pub max_priority_fee_per_gas: Option<U256>,
+    /// Percent by which fees are raised when replacing a stuck transaction
+    pub escalation_percent: u64,
+}
+
+impl GasPolicy {
+    /// Fill in EIP-1559 fees from the node's estimate, capped by the policy
+    pub async fn fill_fees<M: Middleware>(
+        &self,
+        client: &M,
+        tx: &mut TypedTransaction,
+    ) -> anyhow::Result<()> {
+        let TypedTransaction::Eip1559(tx) = tx else {
+            return Ok(());
+        };
+
+        let (max_fee, max_priority_fee) = client
+            .estimate_eip1559_fees(None)
+            .await
+            .map_err(|err| anyhow!("failed to estimate fees: {err}"))?;
+
+        let max_fee = self.max_fee_per_gas.map_or(max_fee, |cap| max_fee.min(cap));
+        let max_priority_fee = self
+            .max_priority_fee_per_gas
+            .map_or(max_priority_fee, |cap| max_priority_fee.min(cap));
+
+        tx.max_fee_per_gas = Some(max_fee);
+        tx.max_priority_fee_per_gas = Some(max_priority_fee.min(max_fee));
+
+        Ok(())
+    }
+
+    /// Fees for the replacement of a pending transaction, or `None` if the caps don't leave
+    /// enough room for nodes to accept it
+    pub fn escalate(&self, max_fee: U256, max_priority_fee: U256) -> Option<(U256, U256)> {
+        let bump = |fee: U256, percent: u64| fee * (100 + percent) / 100;
+        let percent = self.escalation_percent.max(MIN_REPLACEMENT_BUMP);
+
+        let new_max_fee = bump(max_fee, percent);
+        let new_max_fee = self
+            .max_fee_per_gas
+            .map_or(new_max_fee, |cap| new_max_fee.min(cap));
+        let new_max_priority_fee = bump(max_priority_fee, percent);
+        let new_max_priority_fee = self
+            .max_priority_fee_per_gas
+            .map_or(new_max_priority_fee, |cap| new_max_priority_fee.min(cap))
+            .min(new_max_fee);
+
+        if new_max_fee < bump(max_fee, MIN_REPLACEMENT_BUMP)
+            || new_max_priority_fee < bump(max_priority_fee, MIN_REPLACEMENT_BUMP)
+        {
+            return None;
+        }
+
+        Some((new_max_fee, new_max_priority_fee))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChainConfig {
+    /// HTTP json-rpc endpoint
+    pub rpc_http: String,
+    /// Websocket json-rpc endpoint, for subscriptions
+    pub rpc_ws: Option<String>,
+    /// Chain id the rpc endpoint is expected to serve
+    pub chain_id: u64,
+    /// Address of the deployed bounty contract
+    pub contract_address: Address,
+    /// Number of blocks a transaction has to be buried under before it is considered final
+    pub confirmations: u64,
+    /// ERC-20 tokens rewards can be paid in, besides native ETH
+    pub reward_tokens: Vec<Address>,
+    pub gas: GasPolicy,
+}
+
+impl ChainConfig {
+    /// Load the chain settings from environment variables
+    ///
+    /// Only `CONTRACT_ADDRESS` is required, everything else defaults to a local anvil node.
+    pub fn from_env() -> anyhow::Result<Self> {
+        Self::from_env_prefixed("")
+    }
+
+    /// Load the chain settings from environment variables starting with `prefix`, so several
+    /// chains can be configured side by side (`BASE_CHAIN_RPC_URL`, `BASE_CONTRACT_ADDRESS`, ...)
+    pub fn from_env_prefixed(prefix: &str) -> anyhow::Result<Self> {
+        let var = |name: &str| env::var(format!("{prefix}{name}"));
+
+        let contract_address = var("CONTRACT_ADDRESS")
+            .with_context(|| format!("Couldn't get {prefix}CONTRACT_ADDRESS env var"))?
+            .parse()
+            .with_context(|| format!("{prefix}CONTRACT_ADDRESS should be an address"))?;
+
+        let chain_id = match var("CHAIN_ID") {
+            Ok(chain_id) => chain_id
+                .parse()
+                .with_context(|| format!("{prefix}CHAIN_ID should be a number"))?,
+            Err(_) => Chain::AnvilHardhat as u64,
+        };
+
+        let confirmations = match var("CHAIN_CONFIRMATIONS") {
+            Ok(n) => n
+                .parse()
+                .with_context(|| format!("{prefix}CHAIN_CONFIRMATIONS should be a number"))?,
+            Err(_) => 6,
+        };
+
+        let escalation_percent = match var("GAS_ESCALATION_PERCENT") {
+            Ok(percent) => percent
+                .parse()
+                .with_context(|| format!("{prefix}GAS_ESCALATION_PERCENT should be a number"))?,
+            Err(_) => 25,
+        };
+
+        let rpc_http = match var("CHAIN_RPC_URL") {
+            Ok(url) => rpc_url(&url, &["http", "https"])
+                .with_context(|| format!("{prefix}CHAIN_RPC_URL should be an http(s) url"))?,
+            Err(_) => "http://127.0.0.1:8545".to_string(),
+        };
+        let rpc_ws = var("CHAIN_WS_URL")
+            .ok()
+            .filter(|url| !url.trim().is_empty())
+            .map(|url| {
+                rpc_url(&url, &["ws", "wss"])
+                    .with_context(|| format!("{prefix}CHAIN_WS_URL should be a ws(s) url"))
+            })
+            .transpose()?;
+
+        let reward_tokens = var("REWARD_TOKENS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|token| !token.is_empty())
+            .map(|token| {
+                token
+                    .parse()
+                    .with_context(|| format!("{prefix}REWARD_TOKENS should be token addresses"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(ChainConfig {
+            rpc_http,
+            rpc_ws,
+            chain_id,
+            contract_address,
+            confirmations,
+            reward_tokens,
+            gas: GasPolicy {
+                max_fee_per_gas: gwei_from_env(&format!("{prefix}GAS_MAX_FEE_GWEI"))?,
+                max_priority_fee_per_gas: gwei_from_env(&format!(
+                    "{prefix}GAS_MAX_PRIORITY_FEE_GWEI"
+                ))?,
+                escalation_percent,
+            },
+        })
+    }
+
+    pub fn provider(&self) -> anyhow::Result<Provider<Http>> {
+        let provider = Provider::<Http>::try_from(self.rpc_http.as_str())?;
+        Ok(provider)
+    }
+
+    /// Connect to the websocket endpoint, if one is configured
+    pub async fn ws_provider(&self) -> anyhow::Result<Option<Provider<Ws>>> {
+        let Some(rpc_ws) = &self.rpc_ws else {
+            return Ok(None);
+        };
+        let provider = Provider::<Ws>::connect(rpc_ws).await?;
+        Ok(Some(provider))
+    }
+
+    /// Make sure the rpc endpoints serve the chain we are configured for
+    pub async fn validate(&self) -> anyhow::Result<()> {
+        let chain_id = self.provider()?.get_chainid().await?;
+        self.check_chain_id(&self.rpc_http, chain_id)?;
+
+        if let Some(provider) = self.ws_provider().await? {
+            let chain_id = provider.get_chainid().await?;
+            self.check_chain_id(self.rpc_ws.as_deref().unwrap_or_default(), chain_id)?;
+        }
+        Ok(())
+    }
+
+    fn check_chain_id(&self, endpoint: &str, chain_id: U256) -> anyhow::Result<()> {
+        if chain_id != U256::from(self.chain_id) {
+            return Err(anyhow!(
+                "rpc endpoint {} is on chain {} but CHAIN_ID is {}",
+                endpoint,
+                chain_id,
+                self.chain_id
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn read_only_contract(&self) -> anyhow::Result<ReadOnlyContract> {
+        get_read_only_contract(&self.provider()?, self.contract_address)
+    }
+}
+
+/// Check that an rpc endpoint is a url using one of `schemes`
+fn rpc_url(url: &str, schemes: &[&str]) -> anyhow::Result<String> {
+    let url = url.trim();
+    let parsed = Url::parse(url)?;
+    if !schemes.contains(&parsed.scheme()) {
+        return Err(anyhow!("unsupported scheme {}", parsed.scheme()));
+    }
+    if parsed.host_str().is_none() {
+        return Err(anyhow!("missing host"));
+    }
+    Ok(url.to_string())
+}
+
+fn gwei_from_env(name: &str) -> anyhow::Result<Option<U256>> {
+    let Ok(gwei) = env::var(name) else {
+        return Ok(None);
+    };
+    let wei = parse_units(&gwei, "gwei").with_context(|| format!("{name} should be a number"))?;
+    Ok(Some(wei.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::U256;
+
+    use super::rpc_url;
+    use crate::GasPolicy;
+
+    #[test]
+    fn rpc_urls_must_use_the_expected_scheme() {
+        assert_eq!(
+            rpc_url(" https://mainnet.example.org/v3/key ", &["http", "https"]).unwrap(),
+            "https://mainnet.example.org/v3/key"
+        );
+        assert!(rpc_url("wss://mainnet.example.org", &["ws", "wss"]).is_ok());
+
+        assert!(rpc_url("wss://mainnet.example.org", &["http", "https"]).is_err());
+        assert!(rpc_url("http://127.0.0.1:8545", &["ws", "wss"]).is_err());
+        assert!(rpc_url("127.0.0.1:8545", &["http", "https"]).is_err());
+        assert!(rpc_url("not a url", &["ws", "wss"]).is_err());
+    }
+
+    #[test]
+    fn escalate_bumps_fees() {
+        let gas = GasPolicy {
+            escalation_percent: 25,
+            ..Default::default()
+        };
+        assert_eq!(
+            gas.escalate(U256::from(100), U256::from(10)),
+            Some((U256::from(125), U256::from(12)))
+        );
+    }
+
+    #[test]
+    fn escalate_respects_caps() {
+        let gas = GasPolicy {
+            max_fee_per_gas: Some(U256::from(120)),
+            max_priority_fee_per_gas: None,
+            escalation_percent: 25,
+        };
+        assert_eq!(
+            gas.escalate(U256::from(100), U256::from(10)),
+            Some((U256::from(120), U256::from(12)))
+        );
+
+        // already at the cap, a replacement would be rejected
+        assert_eq!(gas.escalate(U256::from(120), U256::from(10)), None);
+    }
+
+    #[test]
+    fn escalate_bumps_at_least_what_nodes_accept() {
+        // below the minimum replacement bump nodes would reject the replacement
+        let gas = GasPolicy {
+            escalation_percent: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            gas.escalate(U256::from(1000), U256::from(100)),
+            Some((U256::from(1100), U256::from(110)))
+        );
+    }
+
+    #[test]
+    fn escalate_keeps_priority_fee_within_max_fee() {
+        let gas = GasPolicy {
+            max_fee_per_gas: None,
+            max_priority_fee_per_gas: Some(U256::from(1000)),
+            escalation_percent: 50,
+        };
+        assert_eq!(
+            gas.escalate(U256::from(100), U256::from(100)),
+            Some((U256::from(150), U256::from(150)))
+        );
+
+        // capping the tip below the minimum bump makes the replacement invalid
+        let gas = GasPolicy {
+            max_priority_fee_per_gas: Some(U256::from(105)),
+            ..gas
+        };
+        assert_eq!(gas.escalate(U256::from(200), U256::from(100)), None);
+    }
+}
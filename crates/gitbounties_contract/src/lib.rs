@@ -1,18 +1,32 @@
 use std::sync::Arc;
 
 pub use ethers::{
+    contract::parse_log,
     middleware::SignerMiddleware,
     prelude::{
         abigen,
         k256::{ecdsa::SigningKey, SecretKey},
         Abigen,
     },
-    providers::{Http, Middleware, Provider},
+    providers::{Http, Middleware, PendingTransaction, Provider},
     signers::{LocalWallet, Signer, Wallet},
     solc::{Artifact, Project, ProjectPathsConfig},
-    types::{Address, Chain, NameOrAddress, H160, U256},
+    types::{
+        transaction::eip2718::TypedTransaction, Address, BlockId, Bytes, Chain, NameOrAddress,
+        Signature, TransactionReceipt, H160, H256, U256,
+    },
+    utils::to_checksum,
 };
 
+mod config;
+pub use config::*;
+
+mod operator;
+pub use operator::*;
+
+mod signer;
+pub use signer::*;
+
 mod abi {
 
     use ethers::prelude::abigen;
@@ -21,7 +35,39 @@ mod abi {
 }
 pub use abi::*;
 
+/// Minimal ERC-20 interface, for bounties rewarded in tokens
+mod erc20_abi {
+    use ethers::prelude::abigen;
+
+    abigen!(
+        IERC20,
+        r#"[
+            function balanceOf(address account) external view returns (uint256)
+            function decimals() external view returns (uint8)
+            function symbol() external view returns (string)
+            function transfer(address to, uint256 amount) external returns (bool)
+        ]"#
+    );
+}
+pub use erc20_abi::IERC20;
+
+/// ERC-6551 token bound account holding the funds of a bounty token
+mod account_abi {
+    use ethers::prelude::abigen;
+
+    abigen!(
+        TokenBoundAccount,
+        r#"[
+            function executeCall(address to, uint256 value, bytes data) external payable returns (bytes)
+            function owner() external view returns (address)
+        ]"#
+    );
+}
+pub use account_abi::TokenBoundAccount;
+
 pub type Contract = GitbountiesNFT<SignerMiddleware<Provider<Http>, Wallet<SigningKey>>>;
+/// Contract handle without a signer, for view calls only
+pub type ReadOnlyContract = GitbountiesNFT<Provider<Http>>;
 
 pub fn parse_address(address: &str) -> anyhow::Result<H160> {
     let res = address.parse()?;
@@ -30,23 +76,136 @@ pub fn parse_address(address: &str) -> anyhow::Result<H160> {
 
 pub async fn get_contract(
     provider: &Provider<Http>,
-    contract_address: &str,
+    contract_address: Address,
     private_key: &str,
+    chain_id: u64,
 ) -> anyhow::Result<Contract> {
-    let wallet: LocalWallet = private_key
-        .parse::<LocalWallet>()?
-        .with_chain_id(Chain::AnvilHardhat);
+    let wallet: LocalWallet = private_key.parse::<LocalWallet>()?.with_chain_id(chain_id);
 
     let client = SignerMiddleware::new(provider.clone(), wallet.clone());
     let client = Arc::new(client);
-    let address: Address = contract_address.parse()?;
-    let contract = GitbountiesNFT::new(address, client);
+    let contract = GitbountiesNFT::new(contract_address, client);
 
     Ok(contract)
 }
 
-pub fn http_provider() -> Provider<Http> {
-    Provider::<Http>::try_from("http://127.0.0.1:8545").unwrap()
+pub fn get_read_only_contract(
+    provider: &Provider<Http>,
+    contract_address: Address,
+) -> anyhow::Result<ReadOnlyContract> {
+    let contract = GitbountiesNFT::new(contract_address, Arc::new(provider.clone()));
+
+    Ok(contract)
+}
+
+/// Where the funds of a bounty token are held
+#[derive(Debug, Clone)]
+pub struct TokenEscrow {
+    /// Current owner of the token
+    pub owner: Address,
+    /// Token bound account holding the funds
+    pub account: Address,
+    /// Balance of the reward asset held by the token bound account
+    pub balance: U256,
+}
+
+/// Look up the owner and funds of a token. Fails if the token does not exist.
+///
+/// `reward_token` is the ERC-20 token the reward is paid in, `None` for native ETH.
+pub async fn token_escrow(
+    contract: &ReadOnlyContract,
+    token_id: U256,
+    reward_token: Option<Address>,
+) -> anyhow::Result<TokenEscrow> {
+    let owner = contract.owner_of(token_id).call().await?;
+    let account = contract.get_account(token_id).call().await?;
+    let balance = account_balance(contract, account, reward_token, None).await?;
+
+    Ok(TokenEscrow {
+        owner,
+        account,
+        balance,
+    })
+}
+
+/// Owner of a token, `None` once it has been burned
+pub async fn token_owner(
+    contract: &ReadOnlyContract,
+    token_id: U256,
+) -> anyhow::Result<Option<Address>> {
+    match contract.owner_of(token_id).call().await {
+        Ok(owner) => Ok(Some(owner)),
+        Err(err) if err.is_revert() => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Balance of the reward asset held by an account, in its smallest unit
+pub async fn account_balance(
+    contract: &ReadOnlyContract,
+    account: Address,
+    reward_token: Option<Address>,
+    block: Option<BlockId>,
+) -> anyhow::Result<U256> {
+    let client = contract.client();
+    let balance = match reward_token {
+        None => client.get_balance(account, block).await?,
+        Some(reward_token) => {
+            let mut call = IERC20::new(reward_token, client).balance_of(account);
+            if let Some(block) = block {
+                call = call.block(block);
+            }
+            call.call().await?
+        },
+    };
+
+    Ok(balance)
+}
+
+/// Symbol of an ERC-20 token
+pub async fn erc20_symbol(contract: &ReadOnlyContract, token: Address) -> anyhow::Result<String> {
+    let symbol = IERC20::new(token, contract.client())
+        .symbol()
+        .call()
+        .await?;
+    Ok(symbol)
+}
+
+/// Decimals of an ERC-20 token. Fails if the address isn't an ERC-20 token.
+pub async fn erc20_decimals(contract: &ReadOnlyContract, token: Address) -> anyhow::Result<u8> {
+    let decimals = IERC20::new(token, contract.client())
+        .decimals()
+        .call()
+        .await?;
+    Ok(decimals)
+}
+
+/// Wait for a transaction to be mined and buried under `confirmations` blocks
+///
+/// Returns `None` if the node doesn't know about the transaction.
+pub async fn wait_for_receipt(
+    provider: &Provider<Http>,
+    tx_hash: H256,
+    confirmations: usize,
+) -> anyhow::Result<Option<TransactionReceipt>> {
+    let receipt = PendingTransaction::new(tx_hash, provider)
+        .confirmations(confirmations)
+        .await?;
+
+    Ok(receipt)
+}
+
+/// Token minted by a transaction, decoded from the `Transfer` log emitted by the contract
+pub fn minted_token(
+    receipt: &TransactionReceipt,
+    contract_address: Address,
+) -> Option<TransferFilter> {
+    receipt
+        .logs
+        .iter()
+        .filter(|log| log.address == contract_address)
+        .filter_map(|log| parse_log::<TransferFilter>(log.clone()).ok())
+        .find(|transfer| transfer.from.is_zero())
 }
 
 #[cfg(test)]
@@ -57,7 +216,7 @@ mod tests {
         utils::WEI_IN_ETHER,
     };
 
-    use crate::{get_contract, http_provider, TransferFilter};
+    use crate::{get_contract, Chain, TransferFilter};
 
     /*
     #[tokio::test]
@@ -73,10 +232,11 @@ mod tests {
     /// Test entire gitbounty nft lifecycle
     #[tokio::test]
     async fn end_to_end() -> anyhow::Result<()> {
-        let provider = http_provider();
+        let provider = Provider::<Http>::try_from("http://127.0.0.1:8545")?;
+        let chain_id = Chain::AnvilHardhat as u64;
 
         // hardcoded address for use with anvil
-        let contract_addr = "0xb19b36b1456E65E3A6D514D3F715f204BD59f431";
+        let contract_addr: Address = "0xb19b36b1456E65E3A6D514D3F715f204BD59f431".parse()?;
         let op_addr: Address = "0xa0Ee7A142d267C1f36714E4a8F75612F20a79720".parse()?;
         let u1_addr: Address = "0x70997970C51812dc3A010C7d01b50e0d17dc79C8".parse()?;
         let u2_addr: Address = "0x3C44CdDdB6a900fa2b585dd299e03d12FA4293BC".parse()?;
@@ -84,8 +244,8 @@ mod tests {
         let u1_key = "0x59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d";
         let u2_key = "0x5de4111afa1a4b94908f83103eb1f1706367c2e68ca870fc3fb9a804cdab365a";
 
-        let u1_contract = get_contract(&provider, contract_addr, u1_key).await?;
-        let op_contract = get_contract(&provider, contract_addr, op_key).await?;
+        let u1_contract = get_contract(&provider, contract_addr, u1_key, chain_id).await?;
+        let op_contract = get_contract(&provider, contract_addr, op_key, chain_id).await?;
 
         let u1_balance = provider.get_balance(u1_addr, None).await?;
         let u2_balance = provider.get_balance(u2_addr, None).await?;
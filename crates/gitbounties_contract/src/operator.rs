@@ -0,0 +1,125 @@
+//! Long-lived client for the operator wallet
+//!
+//! All transactions from the operator go through a single client per chain, so the nonce manager
+//! hands out nonces in order even when several payouts and mints are sent at once.
+
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use ethers::{
+    middleware::{NonceManagerMiddleware, SignerMiddleware},
+    providers::{Http, Middleware, Provider},
+    signers::Signer,
+    types::{
+        transaction::eip2718::TypedTransaction, Address, Eip1559TransactionRequest, H256, U256,
+    },
+};
+
+use crate::{ChainConfig, GasPolicy, GitbountiesNFT, OperatorSigner, TokenBoundAccount, IERC20};
+
+pub type OperatorClient = NonceManagerMiddleware<SignerMiddleware<Provider<Http>, OperatorSigner>>;
+/// Contract handle that sends transactions from the operator wallet
+pub type OperatorContract = GitbountiesNFT<OperatorClient>;
+
+#[derive(Debug, Clone)]
+pub struct Operator {
+    client: Arc<OperatorClient>,
+    gas: GasPolicy,
+}
+
+impl Operator {
+    pub fn new(chain: &ChainConfig, signer: OperatorSigner) -> anyhow::Result<Self> {
+        let signer = signer.with_chain_id(chain.chain_id);
+        let address = signer.address();
+
+        let signer = SignerMiddleware::new(chain.provider()?, signer);
+        let client = NonceManagerMiddleware::new(signer, address);
+
+        Ok(Operator {
+            client: Arc::new(client),
+            gas: chain.gas.clone(),
+        })
+    }
+
+    pub fn address(&self) -> Address {
+        self.client.inner().address()
+    }
+
+    pub fn client(&self) -> &OperatorClient {
+        &self.client
+    }
+
+    pub fn provider(&self) -> &Provider<Http> {
+        self.client.inner().inner()
+    }
+
+    pub fn contract(&self, contract_address: Address) -> OperatorContract {
+        GitbountiesNFT::new(contract_address, self.client.clone())
+    }
+
+    /// Transaction moving ERC-20 tokens out of a token bound account owned by the operator
+    pub fn erc20_sweep_tx(
+        &self,
+        account: Address,
+        token: Address,
+        to: Address,
+        amount: U256,
+    ) -> TypedTransaction {
+        let data = IERC20::new(token, self.client.clone())
+            .transfer(to, amount)
+            .calldata()
+            .expect("Contract call should have calldata");
+
+        TokenBoundAccount::new(account, self.client.clone())
+            .execute_call(token, U256::zero(), data)
+            .tx
+    }
+
+    /// Send a transaction with fees filled in according to the gas policy
+    pub async fn send(&self, tx: impl Into<TypedTransaction>) -> anyhow::Result<H256> {
+        let mut tx = tx.into();
+        self.gas.fill_fees(self.client.as_ref(), &mut tx).await?;
+
+        let pending = self.client.send_transaction(tx, None).await?;
+        Ok(pending.tx_hash())
+    }
+
+    /// Replace a transaction that is still pending with one paying higher fees
+    ///
+    /// Returns the hash of the replacement, or `None` if the transaction was already mined.
+    pub async fn escalate(&self, tx_hash: H256) -> anyhow::Result<Option<H256>> {
+        let tx = self
+            .client
+            .get_transaction(tx_hash)
+            .await?
+            .ok_or_else(|| anyhow!("transaction {tx_hash:?} is unknown to the node"))?;
+        if tx.block_number.is_some() {
+            return Ok(None);
+        }
+
+        let (max_fee, max_priority_fee) = self
+            .gas
+            .escalate(
+                tx.max_fee_per_gas.or(tx.gas_price).unwrap_or_default(),
+                tx.max_priority_fee_per_gas.unwrap_or_default(),
+            )
+            .ok_or_else(|| anyhow!("fee caps leave no room to replace {tx_hash:?}"))?;
+
+        // same nonce, so only one of the two can ever be mined
+        let replacement = Eip1559TransactionRequest {
+            from: Some(tx.from),
+            to: tx.to.map(Into::into),
+            gas: Some(tx.gas),
+            value: Some(tx.value),
+            data: Some(tx.input),
+            nonce: Some(tx.nonce),
+            access_list: tx.access_list.unwrap_or_default(),
+            max_priority_fee_per_gas: Some(max_priority_fee),
+            max_fee_per_gas: Some(max_fee),
+            chain_id: tx.chain_id.map(|chain_id| chain_id.as_u64().into()),
+        };
+        let pending = self.client.send_transaction(replacement, None).await?;
+
+        Ok(Some(pending.tx_hash()))
+    }
+}
@@ -0,0 +1,378 @@
+//! Backends holding the operator key
+//!
+//! The backend is picked with `OPERATOR_SIGNER`:
+//! - `key` (default): raw hex private key in `WALLET_PRIVATE_KEY`
+//! - `keystore`: encrypted JSON keystore at `WALLET_KEYSTORE_PATH`, unlocked with
+//!   `WALLET_KEYSTORE_PASSWORD`
+//! - `mnemonic`: `WALLET_MNEMONIC` phrase, derived at `WALLET_DERIVATION_PATH`
+//! - `remote`: signing service at `REMOTE_SIGNER_URL` holding the key of `REMOTE_SIGNER_ADDRESS`
+//!
+//! The remote signer speaks the Web3Signer eth1 api: `POST {url}/api/v1/eth1/sign/{address}` with
+//! `{"data": "0x.."}` answers with the hex encoded signature of `keccak256(data)`.
+
+use std::{env, time::Duration};
+
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use ethers::{
+    signers::{
+        coins_bip39::English, to_eip155_v, LocalWallet, MnemonicBuilder, Signer, WalletError,
+    },
+    types::{
+        transaction::{eip2718::TypedTransaction, eip712::Eip712},
+        Address, Signature, H256,
+    },
+    utils::{hex, keccak256},
+};
+use serde_json::json;
+
+/// Derivation path of the first account of most wallets
+const DEFAULT_DERIVATION_PATH: &str = "m/44'/60'/0'/0/0";
+/// How long to wait on the remote signer, so a hung service fails the transaction instead of
+/// blocking whatever is sending it
+const REMOTE_SIGNER_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, thiserror::Error)]
+pub enum OperatorSignerError {
+    #[error(transparent)]
+    Wallet(#[from] WalletError),
+    #[error("remote signer request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("remote signer returned an invalid signature: {0}")]
+    InvalidSignature(String),
+    #[error("failed to encode typed data: {0}")]
+    TypedData(String),
+}
+
+/// Signer for the operator wallet, backed by a key held locally or by a remote service
+#[derive(Debug, Clone)]
+pub enum OperatorSigner {
+    Local(LocalWallet),
+    Remote(RemoteSigner),
+}
+
+impl OperatorSigner {
+    /// Load the signer selected by `OPERATOR_SIGNER`
+    pub fn from_env() -> anyhow::Result<Self> {
+        let backend = env::var("OPERATOR_SIGNER").unwrap_or_else(|_| "key".to_string());
+
+        let signer = match backend.as_str() {
+            "key" => {
+                let private_key = env::var("WALLET_PRIVATE_KEY")
+                    .context("Couldn't get WALLET_PRIVATE_KEY env var")?;
+                OperatorSigner::Local(private_key.parse()?)
+            },
+            "keystore" => {
+                let path = env::var("WALLET_KEYSTORE_PATH")
+                    .context("Couldn't get WALLET_KEYSTORE_PATH env var")?;
+                let password = env::var("WALLET_KEYSTORE_PASSWORD")
+                    .context("Couldn't get WALLET_KEYSTORE_PASSWORD env var")?;
+                let wallet = LocalWallet::decrypt_keystore(&path, password)
+                    .with_context(|| format!("Failed to decrypt keystore {path}"))?;
+                OperatorSigner::Local(wallet)
+            },
+            "mnemonic" => {
+                let phrase =
+                    env::var("WALLET_MNEMONIC").context("Couldn't get WALLET_MNEMONIC env var")?;
+                let path = env::var("WALLET_DERIVATION_PATH")
+                    .unwrap_or_else(|_| DEFAULT_DERIVATION_PATH.to_string());
+                OperatorSigner::Local(wallet_from_mnemonic(&phrase, &path)?)
+            },
+            "remote" => {
+                let url = env::var("REMOTE_SIGNER_URL")
+                    .context("Couldn't get REMOTE_SIGNER_URL env var")?;
+                let address = env::var("REMOTE_SIGNER_ADDRESS")
+                    .context("Couldn't get REMOTE_SIGNER_ADDRESS env var")?
+                    .parse()
+                    .context("REMOTE_SIGNER_ADDRESS should be an address")?;
+                OperatorSigner::Remote(RemoteSigner::new(&url, address))
+            },
+            other => return Err(anyhow!("unknown OPERATOR_SIGNER {other}")),
+        };
+
+        Ok(signer)
+    }
+}
+
+pub fn wallet_from_mnemonic(phrase: &str, derivation_path: &str) -> anyhow::Result<LocalWallet> {
+    let wallet = MnemonicBuilder::<English>::default()
+        .phrase(phrase)
+        .derivation_path(derivation_path)?
+        .build()?;
+    Ok(wallet)
+}
+
+#[async_trait]
+impl Signer for OperatorSigner {
+    type Error = OperatorSignerError;
+
+    async fn sign_message<S: Send + Sync + AsRef<[u8]>>(
+        &self,
+        message: S,
+    ) -> Result<Signature, Self::Error> {
+        match self {
+            OperatorSigner::Local(wallet) => Ok(wallet.sign_message(message).await?),
+            OperatorSigner::Remote(remote) => remote.sign_message(message).await,
+        }
+    }
+
+    async fn sign_transaction(&self, tx: &TypedTransaction) -> Result<Signature, Self::Error> {
+        match self {
+            OperatorSigner::Local(wallet) => Ok(wallet.sign_transaction(tx).await?),
+            OperatorSigner::Remote(remote) => remote.sign_transaction(tx).await,
+        }
+    }
+
+    async fn sign_typed_data<T: Eip712 + Send + Sync>(
+        &self,
+        payload: &T,
+    ) -> Result<Signature, Self::Error> {
+        match self {
+            OperatorSigner::Local(wallet) => Ok(wallet.sign_typed_data(payload).await?),
+            OperatorSigner::Remote(remote) => remote.sign_typed_data(payload).await,
+        }
+    }
+
+    fn address(&self) -> Address {
+        match self {
+            OperatorSigner::Local(wallet) => wallet.address(),
+            OperatorSigner::Remote(remote) => remote.address(),
+        }
+    }
+
+    fn chain_id(&self) -> u64 {
+        match self {
+            OperatorSigner::Local(wallet) => wallet.chain_id(),
+            OperatorSigner::Remote(remote) => remote.chain_id(),
+        }
+    }
+
+    fn with_chain_id<T: Into<u64>>(self, chain_id: T) -> Self {
+        match self {
+            OperatorSigner::Local(wallet) => OperatorSigner::Local(wallet.with_chain_id(chain_id)),
+            OperatorSigner::Remote(remote) => {
+                OperatorSigner::Remote(remote.with_chain_id(chain_id))
+            },
+        }
+    }
+}
+
+/// Signer delegating to a Web3Signer style http service
+#[derive(Debug, Clone)]
+pub struct RemoteSigner {
+    client: reqwest::Client,
+    url: String,
+    address: Address,
+    chain_id: u64,
+}
+
+impl RemoteSigner {
+    pub fn new(url: &str, address: Address) -> Self {
+        RemoteSigner {
+            client: reqwest::Client::builder()
+                .timeout(REMOTE_SIGNER_TIMEOUT)
+                .build()
+                .expect("Couldn't build http client"),
+            url: url.trim_end_matches('/').to_string(),
+            address,
+            chain_id: 1,
+        }
+    }
+
+    /// Have the service sign `keccak256(data)`, making sure the signature is from our address
+    async fn sign(&self, data: &[u8]) -> Result<Signature, OperatorSignerError> {
+        let res = self
+            .client
+            .post(format!("{}/api/v1/eth1/sign/{:?}", self.url, self.address))
+            .json(&json!({ "data": format!("0x{}", hex::encode(data)) }))
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        let signature: Signature = res
+            .trim()
+            .trim_matches('"')
+            .parse()
+            .map_err(|err| OperatorSignerError::InvalidSignature(format!("{err}")))?;
+
+        let hash = H256::from(keccak256(data));
+        signature.verify(hash, self.address).map_err(|_| {
+            OperatorSignerError::InvalidSignature(format!(
+                "signature is not from {:?}",
+                self.address
+            ))
+        })?;
+
+        Ok(signature)
+    }
+
+    async fn sign_message<S: AsRef<[u8]>>(
+        &self,
+        message: S,
+    ) -> Result<Signature, OperatorSignerError> {
+        let message = message.as_ref();
+        let mut data = format!("\x19Ethereum Signed Message:\n{}", message.len()).into_bytes();
+        data.extend_from_slice(message);
+
+        self.sign(&data).await
+    }
+
+    async fn sign_transaction(
+        &self,
+        tx: &TypedTransaction,
+    ) -> Result<Signature, OperatorSignerError> {
+        let mut tx = tx.clone();
+        let chain_id = tx.chain_id().map_or(self.chain_id, |id| id.as_u64());
+        tx.set_chain_id(chain_id);
+
+        let mut signature = self.sign(&tx.rlp()).await?;
+
+        // services answer with v as 27/28 or as the bare recovery id
+        let recovery_id = if signature.v >= 27 {
+            signature.v - 27
+        } else {
+            signature.v
+        };
+        signature.v = to_eip155_v(recovery_id as u8, chain_id);
+
+        Ok(signature)
+    }
+
+    async fn sign_typed_data<T: Eip712 + Send + Sync>(
+        &self,
+        payload: &T,
+    ) -> Result<Signature, OperatorSignerError> {
+        let domain_separator = payload
+            .domain_separator()
+            .map_err(|err| OperatorSignerError::TypedData(err.to_string()))?;
+        let struct_hash = payload
+            .struct_hash()
+            .map_err(|err| OperatorSignerError::TypedData(err.to_string()))?;
+
+        let mut data = vec![0x19, 0x01];
+        data.extend_from_slice(&domain_separator);
+        data.extend_from_slice(&struct_hash);
+
+        self.sign(&data).await
+    }
+
+    fn address(&self) -> Address {
+        self.address
+    }
+
+    fn chain_id(&self) -> u64 {
+        self.chain_id
+    }
+
+    fn with_chain_id<T: Into<u64>>(mut self, chain_id: T) -> Self {
+        self.chain_id = chain_id.into();
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::{
+        signers::{LocalWallet, Signer},
+        types::{transaction::eip2718::TypedTransaction, Address, Eip1559TransactionRequest, H256},
+        utils::{hex, keccak256},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::{wallet_from_mnemonic, RemoteSigner, DEFAULT_DERIVATION_PATH};
+
+    const ANVIL_MNEMONIC: &str = "test test test test test test test test test test test junk";
+
+    /// Serve a single Web3Signer style sign request, signing with `wallet`
+    async fn stub_signer(listener: TcpListener, wallet: LocalWallet) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        let mut request = vec![];
+        let body = loop {
+            let mut buf = [0; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+
+            let text = String::from_utf8_lossy(&request);
+            let Some((headers, body)) = text.split_once("\r\n\r\n") else {
+                continue;
+            };
+            let content_length = headers
+                .lines()
+                .find_map(|line| {
+                    line.to_lowercase()
+                        .strip_prefix("content-length:")
+                        .map(|len| len.trim().parse::<usize>().unwrap())
+                })
+                .unwrap();
+            if body.len() >= content_length {
+                break body.to_string();
+            }
+        };
+
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let data = hex::decode(body["data"].as_str().unwrap()).unwrap();
+        let signature = wallet.sign_hash(H256::from(keccak256(data))).unwrap();
+
+        let res = format!("0x{}", hex::encode(signature.to_vec()));
+        stream
+            .write_all(
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{res}",
+                    res.len()
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn remote_signer_signs_transactions() {
+        let wallet = wallet_from_mnemonic(ANVIL_MNEMONIC, DEFAULT_DERIVATION_PATH).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(stub_signer(listener, wallet.clone()));
+
+        let tx: TypedTransaction = Eip1559TransactionRequest::new()
+            .to(Address::repeat_byte(0x11))
+            .value(1_000)
+            .nonce(7)
+            .gas(21_000)
+            .chain_id(31337)
+            .into();
+
+        let remote = RemoteSigner::new(&url, wallet.address()).with_chain_id(31337u64);
+        let signature = remote.sign_transaction(&tx).await.unwrap();
+
+        assert_eq!(signature.recover(tx.sighash()).unwrap(), wallet.address());
+        // matches what the key would have signed locally
+        assert_eq!(
+            signature,
+            wallet
+                .with_chain_id(31337u64)
+                .sign_transaction(&tx)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn mnemonic_derives_first_account() {
+        // default anvil mnemonic
+        let wallet = wallet_from_mnemonic(ANVIL_MNEMONIC, DEFAULT_DERIVATION_PATH).unwrap();
+
+        assert_eq!(
+            wallet.address(),
+            "0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266"
+                .parse()
+                .unwrap()
+        );
+    }
+}